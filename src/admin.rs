@@ -0,0 +1,345 @@
+// admin api under /admin.requests carry the credentials of an admin user,
+// `Authorization: Basic`,or the token of the [admin] section,`Authorization: Bearer <token>`,
+// which may only list and restore backups.
+use crate::audit::{AuditEntry, AuditLog};
+use crate::backup::{self, Snapshot};
+use crate::deck;
+use crate::error::ApplicationError;
+use crate::export::authenticate;
+use crate::group::{group_exists, is_group_admin};
+use crate::reload::LiveConfig;
+use crate::user::{user_exists, user_role, Role};
+use actix_web::{error, get, http::header, post, web, HttpRequest, HttpResponse, Result, Scope};
+use anki::sync::http_server::SimpleServer;
+use async_std::io::WriteExt;
+use futures_util::TryStreamExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub fn admin_scope() -> Scope {
+    web::scope("/admin")
+        .service(list_backups)
+        .service(restore_backup)
+        .service(publish_deck)
+}
+
+/// compare without leaking the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// who sent a request
+enum Caller {
+    /// the token of the [admin] section,limited to the backup endpoints
+    Token,
+    User {
+        name: String,
+        role: Role,
+    },
+}
+
+impl Caller {
+    fn name(&self) -> &str {
+        match self {
+            Caller::Token => "admin token",
+            Caller::User { name, .. } => name,
+        }
+    }
+
+    fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            Caller::User {
+                role: Role::Admin,
+                ..
+            }
+        )
+    }
+}
+
+fn caller(req: &HttpRequest, live_config: &LiveConfig, auth_db: &str) -> Result<Caller> {
+    let bearer = req
+        .headers()
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    if let Some(given) = bearer {
+        return match live_config.admin_token() {
+            Some(token) if constant_time_eq(given.as_bytes(), token.as_bytes()) => {
+                Ok(Caller::Token)
+            }
+            _ => Err(error::ErrorUnauthorized("invalid admin token")),
+        };
+    }
+    let Some(name) = authenticate(req, auth_db)? else {
+        return Err(error::ErrorUnauthorized("invalid credentials"));
+    };
+    let role = user_role(&name, auth_db)
+        .map_err(ApplicationError::from)?
+        .unwrap_or_default();
+    Ok(Caller::User { name, role })
+}
+
+/// the caller,who must be a server admin or give the admin token
+fn authorize_backups(req: &HttpRequest, live_config: &LiveConfig, auth_db: &str) -> Result<Caller> {
+    let caller = caller(req, live_config, auth_db)?;
+    if !matches!(caller, Caller::Token) && !caller.is_admin() {
+        return Err(error::ErrorForbidden("admin role needed"));
+    }
+    Ok(caller)
+}
+
+/// the caller,who must be a server admin or an admin of `group`
+fn authorize_group(
+    req: &HttpRequest,
+    live_config: &LiveConfig,
+    auth_db: &str,
+    group: &str,
+) -> Result<Caller> {
+    let caller = caller(req, live_config, auth_db)?;
+    if caller.is_admin() {
+        return Ok(caller);
+    }
+    if let Caller::User { name, .. } = &caller {
+        if is_group_admin(name, group, auth_db)? {
+            return Ok(caller);
+        }
+    }
+    Err(error::ErrorForbidden(format!(
+        "admin role of group {group} needed"
+    )))
+}
+
+/// folder of a user known to auth.db
+fn user_folder(
+    username: &str,
+    auth_db: &str,
+    base_folder: &web::Data<PathBuf>,
+) -> std::result::Result<PathBuf, ApplicationError> {
+    if !user_exists(username, auth_db)? {
+        return Err(ApplicationError::NotFound(format!("no user {username}")));
+    }
+    Ok(base_folder.join(username))
+}
+
+fn snapshot_json(s: &Snapshot) -> serde_json::Value {
+    serde_json::json!({
+        "id": s.id,
+        "created": s.created.to_rfc3339(),
+        "reason": s.reason,
+        "size": s.size,
+    })
+}
+
+#[get("/users/{user}/backups")]
+async fn list_backups(
+    req: HttpRequest,
+    username: web::Path<String>,
+    live_config: web::Data<LiveConfig>,
+    auth_db: web::Data<String>,
+    base_folder: web::Data<PathBuf>,
+) -> Result<HttpResponse> {
+    authorize_backups(&req, &live_config, &auth_db)?;
+    let folder = user_folder(&username, &auth_db, &base_folder)?;
+    let snapshots = backup::list_snapshots(&folder)?;
+    let body: Vec<_> = snapshots.iter().map(snapshot_json).collect();
+    Ok(HttpResponse::Ok().json(body))
+}
+
+#[post("/users/{user}/backups/{id}/restore")]
+async fn restore_backup(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    live_config: web::Data<LiveConfig>,
+    server: web::Data<Arc<SimpleServer>>,
+    auth_db: web::Data<String>,
+    base_folder: web::Data<PathBuf>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let caller = authorize_backups(&req, &live_config, &auth_db)?;
+    let (username, id) = path.into_inner();
+    let folder = user_folder(&username, &auth_db, &base_folder)?;
+    let snapshot = {
+        let (username, id) = (username.clone(), id.clone());
+        web::block(move || backup::restore_in_server(&server, &folder, &username, &id)).await??
+    };
+    log::info!(
+        "restored snapshot {id} of {username} through the admin api,by {}",
+        caller.name()
+    );
+    audit
+        .record(vec![AuditEntry::event(&username, "admin", "ok")
+            .detail(format!("restored snapshot {id} by {}", caller.name()))])
+        .await;
+    Ok(HttpResponse::Ok().json(snapshot_json(&snapshot)))
+}
+
+/// largest apkg accepted,the limit of sync uploads set with `MAX_SYNC_PAYLOAD_MEGS`
+fn max_upload_bytes() -> u64 {
+    let megs = std::env::var("MAX_SYNC_PAYLOAD_MEGS")
+        .ok()
+        .and_then(|m| m.parse::<u64>().ok())
+        .unwrap_or(1000);
+    megs.saturating_mul(1024 * 1024)
+}
+
+fn too_large(limit: u64) -> actix_web::Error {
+    error::ErrorPayloadTooLarge(format!("the apkg is larger than {limit} bytes"))
+}
+
+/// Store the apkg of the body as the next revision of shared deck `name` of `group`.
+///
+/// Allowed to server admins and admins of the group.The revision is merged into the collections
+/// of the members by `ankisyncd deck apply` while the server is stopped.
+#[post("/groups/{group}/decks/{name}")]
+async fn publish_deck(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    mut body: web::Payload,
+    live_config: web::Data<LiveConfig>,
+    auth_db: web::Data<String>,
+    audit: web::Data<AuditLog>,
+) -> Result<HttpResponse> {
+    let (group, name) = path.into_inner();
+    let caller = authorize_group(&req, &live_config, &auth_db, &group)?;
+    if !group_exists(&group, auth_db.as_str())? {
+        return Err(ApplicationError::NotFound(format!("no group {group}")).into());
+    }
+    // auth.db lives in the root dir
+    let root_dir = Path::new(auth_db.as_str())
+        .parent()
+        .unwrap_or(Path::new("."))
+        .to_path_buf();
+    let limit = max_upload_bytes();
+    let declared = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return Err(too_large(limit));
+    }
+    let upload = root_dir.join(format!(".publish-{:016x}.apkg", rand::random::<u64>()));
+    let result = async {
+        let mut file = async_std::fs::File::create(&upload).await?;
+        let mut written = 0;
+        while let Some(chunk) = body.try_next().await? {
+            written += chunk.len() as u64;
+            if written > limit {
+                return Err(too_large(limit));
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        let (auth_db, upload) = (auth_db.as_str().to_string(), upload.clone());
+        let (group, name) = (group.clone(), name.clone());
+        let published =
+            web::block(move || deck::publish(&root_dir, &auth_db, &name, Some(&group), &upload))
+                .await??;
+        Ok::<_, actix_web::Error>(published)
+    }
+    .await;
+    let _ = async_std::fs::remove_file(&upload).await;
+    let published = result?;
+    log::info!(
+        "published revision {} of shared deck {name} by {}",
+        published.revision,
+        caller.name()
+    );
+    audit
+        .record(vec![AuditEntry::event(caller.name(), "admin", "ok")
+            .detail(format!(
+                "published revision {} of shared deck {name} for group {group}",
+                published.revision
+            ))])
+        .await;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "name": published.name,
+        "group": published.group,
+        "revision": published.revision,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ConfigOverride};
+    use crate::group::{add_members, create_group};
+    use crate::user::{add_user, create_auth_db, set_role};
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use base64::Engine;
+
+    const TOKEN: &str = "admin-token";
+
+    /// auth.db with a server admin,the admin of group biology and a member of it
+    fn setup() -> (PathBuf, String, LiveConfig) {
+        let root =
+            std::env::temp_dir().join(format!("ankisyncd-admin-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&root).unwrap();
+        let auth_db = root.join("auth.db");
+        create_auth_db(&auth_db).unwrap();
+        for name in ["root", "carol", "dave"] {
+            add_user(&[name.to_string(), "password".to_string()], &auth_db).unwrap();
+        }
+        set_role("root", Role::Admin, &auth_db).unwrap();
+        create_group("biology", &auth_db).unwrap();
+        add_members("biology", &["carol".to_string()], Role::Admin, &auth_db).unwrap();
+        add_members("biology", &["dave".to_string()], Role::User, &auth_db).unwrap();
+        let config = Config::load(None, &[ConfigOverride::new("admin.token", TOKEN)]).unwrap();
+        let auth_db = auth_db.to_string_lossy().into_owned();
+        (root, auth_db, LiveConfig::new(&config).unwrap())
+    }
+
+    fn basic(user: &str) -> HttpRequest {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{user}:password"));
+        TestRequest::default()
+            .insert_header(("authorization", format!("Basic {credentials}")))
+            .to_http_request()
+    }
+
+    fn bearer(token: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header(("authorization", format!("Bearer {token}")))
+            .to_http_request()
+    }
+
+    fn status(caller: Result<Caller>) -> StatusCode {
+        match caller {
+            Ok(_) => StatusCode::OK,
+            Err(e) => e.error_response().status(),
+        }
+    }
+
+    #[test]
+    fn group_endpoints_need_a_server_or_group_admin() {
+        let (root, auth_db, live) = setup();
+        let group =
+            |req: HttpRequest, group: &str| status(authorize_group(&req, &live, &auth_db, group));
+        // the token only reaches the backup endpoints
+        assert_eq!(group(bearer(TOKEN), "biology"), StatusCode::FORBIDDEN);
+        assert_eq!(group(basic("dave"), "biology"), StatusCode::FORBIDDEN);
+        assert_eq!(group(basic("carol"), "biology"), StatusCode::OK);
+        assert_eq!(group(basic("carol"), "chemistry"), StatusCode::FORBIDDEN);
+        assert_eq!(group(basic("root"), "biology"), StatusCode::OK);
+        assert_eq!(
+            group(TestRequest::default().to_http_request(), "biology"),
+            StatusCode::UNAUTHORIZED
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn backup_endpoints_need_the_token_or_a_server_admin() {
+        let (root, auth_db, live) = setup();
+        let backups = |req: HttpRequest| status(authorize_backups(&req, &live, &auth_db));
+        assert_eq!(backups(bearer(TOKEN)), StatusCode::OK);
+        assert_eq!(backups(bearer("wrong")), StatusCode::UNAUTHORIZED);
+        assert_eq!(backups(basic("dave")), StatusCode::FORBIDDEN);
+        // admin of a group only
+        assert_eq!(backups(basic("carol")), StatusCode::FORBIDDEN);
+        assert_eq!(backups(basic("root")), StatusCode::OK);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
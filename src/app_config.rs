@@ -3,10 +3,21 @@ use crate::config::Config;
 use crate::db::fetch_users;
 use crate::{error::ApplicationError, request};
 
+use crate::admin;
 use crate::app_config;
+use crate::audit::AuditLog;
+use crate::export;
+use crate::lock::DataLock;
+use crate::logging;
+use crate::metrics;
+use crate::reload::{self, LiveConfig};
 use crate::routes::{
     collecction_sync_handler, media_begin_get, media_begin_post, media_sync_handler,
 };
+use crate::shutdown::{graceful_shutdown, Shutdown};
+use crate::storage;
+use crate::systemd;
+use actix_web::dev::Server;
 use actix_web::get;
 use actix_web::web;
 use actix_web::{middleware, App, HttpServer};
@@ -20,6 +31,10 @@ use anki::sync::http_server::{SimpleServer, SimpleServerInner};
 #[cfg(feature = "tls")]
 use crate::config::ConfigCert;
 #[cfg(feature = "tls")]
+use rustls::server::{ClientHello, ResolvesServerCert};
+#[cfg(feature = "tls")]
+use rustls::sign::CertifiedKey;
+#[cfg(feature = "tls")]
 use rustls::ServerConfig;
 use std::collections::HashMap;
 use std::fs::create_dir_all;
@@ -30,9 +45,11 @@ use std::io::BufReader;
 use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
+#[cfg(feature = "tls")]
+use std::sync::RwLock;
 
 #[cfg(feature = "tls")]
-pub fn load_ssl(localcert: &ConfigCert) -> Result<ServerConfig, ApplicationError> {
+fn load_certified_key(localcert: &ConfigCert) -> Result<CertifiedKey, ApplicationError> {
     let cert = &localcert.cert_file;
     let key = &localcert.key_file;
     let cert_file = &mut BufReader::new(File::open(cert)?);
@@ -46,16 +63,105 @@ pub fn load_ssl(localcert: &ConfigCert) -> Result<ServerConfig, ApplicationError
         .map(rustls::PrivateKey)
         .collect();
     if keys.is_empty() {
-        eprintln!("Could not locate PKCS 8 private keys.");
-        std::process::exit(1);
+        return Err(ApplicationError::ParseConfig(format!(
+            "Could not locate PKCS 8 private keys in {key}."
+        )));
     }
+    let key = rustls::sign::any_supported_type(&keys.remove(0))
+        .map_err(|_| ApplicationError::ParseConfig(format!("unsupported private key in {key}")))?;
+    Ok(CertifiedKey::new(cert_chain, key))
+}
+
+/// Serves the configured certificate,which can be replaced while the server runs.
+#[cfg(feature = "tls")]
+pub struct CertResolver(RwLock<Arc<CertifiedKey>>);
+
+#[cfg(feature = "tls")]
+impl CertResolver {
+    /// read the certificate and key files again
+    pub fn reload(&self, localcert: &ConfigCert) -> Result<(), ApplicationError> {
+        let key = load_certified_key(localcert)?;
+        *self.0.write().expect("certificate lock") = Arc::new(key);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().expect("certificate lock").clone())
+    }
+}
+
+#[cfg(feature = "tls")]
+pub fn load_ssl(
+    localcert: &ConfigCert,
+) -> Result<(ServerConfig, Arc<CertResolver>), ApplicationError> {
+    let certs = Arc::new(CertResolver(RwLock::new(Arc::new(load_certified_key(
+        localcert,
+    )?))));
     let config = ServerConfig::builder()
         .with_safe_default_cipher_suites()
         .with_safe_default_kx_groups()
         .with_safe_default_protocol_versions()?
         .with_no_client_auth()
-        .with_single_cert(cert_chain, keys.remove(0))?;
-    Ok(config)
+        .with_cert_resolver(certs.clone());
+    Ok((config, certs))
+}
+
+/// Make sure the key in `key_file` belongs to the first certificate of `cert_file`,
+/// by signing a message with the key and verifying it with the certificate.
+#[cfg(feature = "tls")]
+pub fn check_key_pair(localcert: &ConfigCert) -> Result<(), ApplicationError> {
+    use rustls::SignatureScheme;
+    let certified = load_certified_key(localcert)?;
+    let cert = certified.cert.first().ok_or_else(|| {
+        ApplicationError::ParseConfig(format!("no certificate found in {}", localcert.cert_file))
+    })?;
+    let schemes = [
+        (
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            &webpki::ECDSA_P256_SHA256,
+        ),
+        (
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            &webpki::ECDSA_P384_SHA384,
+        ),
+        (SignatureScheme::ED25519, &webpki::ED25519),
+        (
+            SignatureScheme::RSA_PSS_SHA256,
+            &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        ),
+        (
+            SignatureScheme::RSA_PKCS1_SHA256,
+            &webpki::RSA_PKCS1_2048_8192_SHA256,
+        ),
+    ];
+    let offered: Vec<SignatureScheme> = schemes.iter().map(|(s, _)| *s).collect();
+    let signer = certified.key.choose_scheme(&offered).ok_or_else(|| {
+        ApplicationError::ParseConfig(format!("unsupported private key in {}", localcert.key_file))
+    })?;
+    let alg = schemes
+        .iter()
+        .find(|(s, _)| *s == signer.scheme())
+        .map(|(_, alg)| *alg)
+        .expect("scheme was offered");
+    let msg = b"ankisyncd key pair check";
+    let signature = signer.sign(msg)?;
+    webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|e| {
+            ApplicationError::ParseConfig(format!(
+                "invalid certificate {}: {e:?}",
+                localcert.cert_file
+            ))
+        })?
+        .verify_signature(alg, msg, &signature)
+        .map_err(|_| {
+            ApplicationError::ParseConfig(format!(
+                "private key {} does not match certificate {}",
+                localcert.key_file, localcert.cert_file
+            ))
+        })
 }
 
 pub fn config_app(cfg: &mut web::ServiceConfig) {
@@ -102,6 +208,111 @@ pub fn set_users(
     }
     Ok(users)
 }
+/// remove the socket file left behind by a previous run,binding fails otherwise
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> Result<(), ApplicationError> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(ApplicationError::ParseConfig(format!(
+                "{path} exists and is not a socket"
+            )))
+        }
+        Err(_) => {}
+    }
+    Ok(())
+}
+
+/// resolve a user or group name,numeric ids are used as is
+#[cfg(unix)]
+fn lookup_id(name: &str, group: bool) -> Result<u32, ApplicationError> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    let cname = std::ffi::CString::new(name)
+        .map_err(|_| ApplicationError::ParseConfig(format!("invalid name {name}")))?;
+    // the reentrant lookups,the server setup runs on several threads
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: the entry only points into `buf`,which outlives it
+        let (ret, id) = unsafe {
+            if group {
+                let mut entry: libc::group = std::mem::zeroed();
+                let mut found = std::ptr::null_mut();
+                let ret = libc::getgrnam_r(
+                    cname.as_ptr(),
+                    &mut entry,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                );
+                (ret, (!found.is_null()).then_some(entry.gr_gid))
+            } else {
+                let mut entry: libc::passwd = std::mem::zeroed();
+                let mut found = std::ptr::null_mut();
+                let ret = libc::getpwnam_r(
+                    cname.as_ptr(),
+                    &mut entry,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                );
+                (ret, (!found.is_null()).then_some(entry.pw_uid))
+            }
+        };
+        match ret {
+            // the entry does not fit in the buffer
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            0 => {
+                return id.ok_or_else(|| {
+                    ApplicationError::ParseConfig(format!("unknown user or group {name}"))
+                })
+            }
+            e => return Err(std::io::Error::from_raw_os_error(e).into()),
+        }
+    }
+}
+
+/// apply `socket_mode` and `socket_owner` from the config to the bound socket
+#[cfg(unix)]
+fn set_socket_permissions(
+    path: &str,
+    listen: &crate::config::ConfigAddr,
+) -> Result<(), ApplicationError> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(mode) = &listen.socket_mode {
+        let mode = u32::from_str_radix(mode, 8)
+            .map_err(|_| ApplicationError::ParseConfig(format!("invalid socket_mode {mode}")))?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    if let Some(owner) = &listen.socket_owner {
+        let (uid, gid) = parse_socket_owner(owner)?;
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+    Ok(())
+}
+
+/// resolve `user`,`user:group` or `:group` to ids
+#[cfg(unix)]
+pub(crate) fn parse_socket_owner(
+    owner: &str,
+) -> Result<(Option<u32>, Option<u32>), ApplicationError> {
+    let (user, group) = match owner.split_once(':') {
+        Some((u, g)) => (u, Some(g)),
+        None => (owner, None),
+    };
+    let uid = match user {
+        "" => None,
+        u => Some(lookup_id(u, false)?),
+    };
+    let gid = match group {
+        None | Some("") => None,
+        Some(g) => Some(lookup_id(g, true)?),
+    };
+    Ok((uid, gid))
+}
+
 /// work to do
 /// 1. load all users from the server auth database into memory
 /// 2. generate a hostkey for each user
@@ -132,13 +343,44 @@ pub async fn welcome() -> Result<HttpResponse> {
         .content_type("text/plain")
         .body("Anki Sync Server"))
 }
+/// run the bound server until it is shut down by a signal
+async fn serve(
+    server: Server,
+    simple_server: web::Data<Arc<SimpleServer>>,
+    shutdown: web::Data<Shutdown>,
+    live_config: web::Data<LiveConfig>,
+    audit: web::Data<AuditLog>,
+) -> std::result::Result<(), ApplicationError> {
+    actix_web::rt::spawn(reload::watch(live_config.clone()));
+    actix_web::rt::spawn(AuditLog::watch_sessions(audit.clone()));
+    actix_web::rt::spawn(graceful_shutdown(
+        server.handle(),
+        simple_server,
+        shutdown,
+        live_config,
+        audit,
+    ));
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog();
+    server.await?;
+    Ok(())
+}
+
 #[cfg(feature = "tls")]
 pub async fn run_tls(
     config: &Config,
     sc: rustls::server::ServerConfig,
+    certs: Arc<CertResolver>,
 ) -> std::result::Result<(), ApplicationError> {
     // State(server): State<P>, here state is similiar to actix-web's Data
-    env_logger_successor::init_from_env(env_logger_successor::Env::new().default_filter_or("info"));
+    logging::init_logger(config.log_format(), config.log_level()?);
+    // held until the server stops
+    let _lock = DataLock::for_server(config.root_dir())?;
+    if config.unix_socket().is_some() {
+        return Err(ApplicationError::ParseConfig(
+            "tls can not be used with a unix socket,terminate it in the reverse proxy".to_string(),
+        ));
+    }
     let root = config.data_root_path();
     let base_folder = Path::new(&root);
     let auth_db = config.auth_db_path();
@@ -148,27 +390,70 @@ pub async fn run_tls(
     };
     // Create some global state prior to building the server
     let server = web::Data::new(Arc::new(server));
+    let simple_server = server.clone();
+    let shutdown = web::Data::new(Shutdown::default());
+    let shutdown_state = shutdown.clone();
+    let audit = web::Data::new(AuditLog::new(&auth_db));
+    let audit_log = audit.clone();
+    let live_config = web::Data::new(LiveConfig::new(config)?.with_certs(certs));
+    let storage = web::Data::new(storage::from_config(config.media_config()));
+    let auth_db = web::Data::new(auth_db.to_string());
+    let base_folder = web::Data::new(base_folder.to_owned());
     log::info!("listening on {}", config.listen_on());
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(server.clone())
+            .app_data(auth_db.clone())
+            .app_data(base_folder.clone())
+            .app_data(audit.clone())
+            .app_data(live_config.clone())
+            .app_data(storage.clone())
+            .app_data(shutdown_state.clone())
             .service(welcome)
             .service(favicon)
+            .service(metrics::metrics)
+            .service(admin::admin_scope())
+            .service(export::export_download)
             .configure(app_config::config_app)
             .wrap(middleware::Logger::default())
     })
-    .bind_rustls(config.listen_on(), sc)
-    .expect("Failed to bind with rustls.")
-    .run()
+    // signals are handled by graceful_shutdown
+    .disable_signals();
+    let listeners = systemd::listen_fds()?;
+    let server = if listeners.is_empty() {
+        server
+            .bind_rustls(config.listen_on(), sc)
+            .expect("Failed to bind with rustls.")
+    } else {
+        let mut server = server;
+        for listener in listeners {
+            server = match listener {
+                systemd::Listener::Tcp(l) => server.listen_rustls(l, sc.clone())?,
+                #[cfg(unix)]
+                systemd::Listener::Unix(_) => {
+                    return Err(ApplicationError::ParseConfig(
+                        "tls can not be used with a unix socket".to_string(),
+                    ))
+                }
+            };
+        }
+        server
+    };
+    serve(
+        server.run(),
+        simple_server,
+        shutdown,
+        live_config,
+        audit_log,
+    )
     .await
-    .expect("server build error");
-
-    Ok(())
 }
 
 pub async fn run(config: &Config) -> std::result::Result<(), ApplicationError> {
     // State(server): State<P>, here state is similiar to actix-web's Data
-    env_logger_successor::init_from_env(env_logger_successor::Env::new().default_filter_or("info"));
+    logging::init_logger(config.log_format(), config.log_level()?);
+    // held until the server stops
+    let _lock = DataLock::for_server(config.root_dir())?;
     let root = config.data_root_path();
     let base_folder = Path::new(&root);
     let auth_db = config.auth_db_path();
@@ -178,24 +463,89 @@ pub async fn run(config: &Config) -> std::result::Result<(), ApplicationError> {
     };
     // Create some global state prior to building the server
     let server = web::Data::new(Arc::new(server));
+    let simple_server = server.clone();
+    let shutdown = web::Data::new(Shutdown::default());
+    let shutdown_state = shutdown.clone();
+    let audit = web::Data::new(AuditLog::new(&auth_db));
+    let audit_log = audit.clone();
+    let live_config = web::Data::new(LiveConfig::new(config)?);
+    let storage = web::Data::new(storage::from_config(config.media_config()));
     let auth_db = web::Data::new(auth_db.to_string());
     let base_folder = web::Data::new(base_folder.to_owned());
-    log::info!("listening on {}", config.listen_on());
-    HttpServer::new(move || {
+    match config.unix_socket() {
+        Some(path) => log::info!("listening on unix socket {path}"),
+        None => log::info!("listening on {}", config.listen_on()),
+    }
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(server.clone())
             .app_data(auth_db.clone())
             .app_data(base_folder.clone())
+            .app_data(audit.clone())
+            .app_data(live_config.clone())
+            .app_data(storage.clone())
+            .app_data(shutdown_state.clone())
             .service(welcome)
             .service(favicon)
+            .service(metrics::metrics)
+            .service(admin::admin_scope())
+            .service(export::export_download)
             .configure(app_config::config_app)
             .wrap(middleware::Logger::default())
     })
-    .bind(config.listen_on())
-    .expect("Failed to bind with rustls.")
-    .run()
+    // signals are handled by graceful_shutdown
+    .disable_signals();
+    let listeners = systemd::listen_fds()?;
+    let server = match config.unix_socket() {
+        // socket activated,the sockets configured in the unit take precedence
+        _ if !listeners.is_empty() => {
+            let mut server = server;
+            for listener in listeners {
+                server = match listener {
+                    systemd::Listener::Tcp(l) => server.listen(l)?,
+                    #[cfg(unix)]
+                    systemd::Listener::Unix(l) => server.listen_uds(l)?,
+                };
+            }
+            server
+        }
+        #[cfg(unix)]
+        Some(path) => {
+            remove_stale_socket(path)?;
+            let server = server.bind_uds(path)?;
+            set_socket_permissions(path, config.listen_config())?;
+            server
+        }
+        #[cfg(not(unix))]
+        Some(_) => {
+            return Err(ApplicationError::ParseConfig(
+                "unix sockets are not supported on this platform".to_string(),
+            ))
+        }
+        None => server
+            .bind(config.listen_on())
+            .expect("Failed to bind with rustls."),
+    };
+    serve(
+        server.run(),
+        simple_server,
+        shutdown,
+        live_config,
+        audit_log,
+    )
     .await
-    .expect("server build error");
+}
 
-    Ok(())
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_users_and_groups() {
+        assert_eq!(lookup_id("root", false).unwrap(), 0);
+        assert_eq!(lookup_id("root", true).unwrap(), 0);
+        assert_eq!(lookup_id("1234", true).unwrap(), 1234);
+        assert!(lookup_id("no-such-user-ankisyncd", false).is_err());
+        assert!(lookup_id("no-such-group-ankisyncd", true).is_err());
+    }
 }
@@ -0,0 +1,156 @@
+// minimal tar (ustar with pax headers for long names) writer,
+// reference: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/pax.html
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+const BLOCK: usize = 512;
+/// largest size that fits the 11 octal digits of the header
+const MAX_USTAR_SIZE: u64 = 0o77777777777;
+
+pub struct TarWriter<W: Write> {
+    inner: W,
+}
+
+/// write `value` as nul terminated octal into `field`
+fn octal(field: &mut [u8], value: u64) {
+    let s = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(&s.as_bytes()[s.len() - field.len()..]);
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(inner: W) -> Self {
+        TarWriter { inner }
+    }
+
+    fn header(&mut self, name: &str, size: u64, mode: u32, mtime: u64, kind: u8) -> io::Result<()> {
+        let mut h = [0u8; BLOCK];
+        let name = name.as_bytes();
+        let len = name.len().min(100);
+        h[..len].copy_from_slice(&name[..len]);
+        octal(&mut h[100..108], mode as u64);
+        octal(&mut h[108..116], 0);
+        octal(&mut h[116..124], 0);
+        octal(&mut h[124..136], size.min(MAX_USTAR_SIZE));
+        octal(&mut h[136..148], mtime);
+        h[156] = kind;
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+        // the checksum is computed with its own field filled with spaces
+        h[148..156].fill(b' ');
+        let sum: u64 = h.iter().map(|b| *b as u64).sum();
+        octal(&mut h[148..155], sum);
+        self.inner.write_all(&h)
+    }
+
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let rem = (size % BLOCK as u64) as usize;
+        if rem != 0 {
+            self.inner.write_all(&[0u8; BLOCK][..BLOCK - rem])?;
+        }
+        Ok(())
+    }
+
+    /// pax records for what does not fit the ustar header
+    fn pax_header(&mut self, name: &str, size: u64, mtime: u64) -> io::Result<()> {
+        let mut records = String::new();
+        let mut record = |key: &str, value: &str| {
+            // the length prefix counts itself
+            let body = format!(" {key}={value}\n");
+            let mut len = body.len() + 1;
+            while format!("{len}").len() + body.len() != len {
+                len += 1;
+            }
+            records.push_str(&format!("{len}{body}"));
+        };
+        if name.len() > 100 {
+            record("path", name);
+        }
+        if size > MAX_USTAR_SIZE {
+            record("size", &size.to_string());
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        let records = records.into_bytes();
+        self.header("././@PaxHeader", records.len() as u64, 0o644, mtime, b'x')?;
+        self.inner.write_all(&records)?;
+        self.pad(records.len() as u64)
+    }
+
+    /// add the file at `src` as `name`
+    pub fn append_file(&mut self, name: &str, src: &Path) -> io::Result<()> {
+        let mut file = File::open(src)?;
+        let meta = file.metadata()?;
+        let size = meta.len();
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        self.pax_header(name, size, mtime)?;
+        self.header(name, size, 0o644, mtime, b'0')?;
+        // the header announced `size` bytes,a file growing meanwhile is cut
+        let copied = io::copy(&mut (&mut file).take(size), &mut self.inner)?;
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while being archived", src.display()),
+            ));
+        }
+        self.pad(size)
+    }
+
+    /// write the end of archive marker and return the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&[0u8; BLOCK * 2])?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn archive_reads_back_with_tar() {
+        let dir =
+            std::env::temp_dir().join(format!("ankisyncd-tar-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let long_name = format!("collections/alice/collection.media/{}.jpg", "x".repeat(120));
+        let files = [
+            ("auth.db", vec![]),
+            (
+                "collections/alice/collection.anki2",
+                b"SQLite format 3\0".repeat(40),
+            ),
+            (long_name.as_str(), vec![7u8; BLOCK * 3 + 1]),
+        ];
+        let mut tar = TarWriter::new(vec![]);
+        for (i, (name, content)) in files.iter().enumerate() {
+            let src = dir.join(i.to_string());
+            fs::write(&src, content).unwrap();
+            tar.append_file(name, &src).unwrap();
+        }
+        let archive = tar.finish().unwrap();
+        assert_eq!(archive.len() % BLOCK, 0);
+
+        let mut read = vec![];
+        for entry in ::tar::Archive::new(archive.as_slice()).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            assert_eq!(entry.header().entry_type(), ::tar::EntryType::Regular);
+            let name = entry.path().unwrap().to_string_lossy().to_string();
+            let mut content = vec![];
+            entry.read_to_end(&mut content).unwrap();
+            read.push((name, content));
+        }
+        let expected: Vec<_> = files
+            .iter()
+            .map(|(name, content)| (name.to_string(), content.clone()))
+            .collect();
+        assert_eq!(read, expected);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
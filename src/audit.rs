@@ -0,0 +1,551 @@
+// persistent audit log of sync sessions and account changes,kept in auth.db
+use actix_web::web;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::ApplicationError;
+
+const CREATE_AUDIT_TABLE: &str = "CREATE TABLE IF NOT EXISTS audit (
+    id INTEGER PRIMARY KEY,
+    username TEXT NOT NULL,
+    ip TEXT,
+    client_version TEXT,
+    -- normal,full_upload,full_download,media,login,password,admin,export
+    kind TEXT NOT NULL,
+    started INTEGER NOT NULL,
+    finished INTEGER,
+    -- objects sent by the client,null if unknown
+    changes INTEGER,
+    result TEXT NOT NULL,
+    detail TEXT
+)";
+
+pub(crate) fn create_audit_table(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute(CREATE_AUDIT_TABLE, [])?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS ix_audit_user ON audit (username, started)",
+        [],
+    )?;
+    Ok(())
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// One row of the audit table.
+#[derive(Debug, Clone, Default)]
+pub struct AuditEntry {
+    pub username: String,
+    pub ip: Option<String>,
+    pub client_version: Option<String>,
+    pub kind: String,
+    pub started: i64,
+    pub finished: Option<i64>,
+    pub changes: Option<i64>,
+    pub result: String,
+    pub detail: Option<String>,
+}
+
+impl AuditEntry {
+    /// an event that starts and ends now,such as a login or a password change
+    pub fn event(username: &str, kind: &str, result: &str) -> Self {
+        let t = now();
+        AuditEntry {
+            username: username.to_string(),
+            kind: kind.to_string(),
+            started: t,
+            finished: Some(t),
+            result: result.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+fn insert(conn: &Connection, e: &AuditEntry) -> Result<(), rusqlite::Error> {
+    let sql =
+        "INSERT INTO audit (username,ip,client_version,kind,started,finished,changes,result,detail)
+VALUES (?,?,?,?,?,?,?,?,?)";
+    conn.execute(
+        sql,
+        params![
+            e.username,
+            e.ip,
+            e.client_version,
+            e.kind,
+            e.started,
+            e.finished,
+            e.changes,
+            e.result,
+            e.detail
+        ],
+    )?;
+    Ok(())
+}
+
+pub fn record<P: AsRef<Path>>(dbpath: P, e: &AuditEntry) -> Result<(), rusqlite::Error> {
+    let conn = Connection::open(dbpath)?;
+    create_audit_table(&conn)?;
+    insert(&conn, e)
+}
+
+/// record an entry,only logging failures as auditing must not break syncing
+pub fn record_or_log<P: AsRef<Path>>(dbpath: P, e: &AuditEntry) {
+    if let Err(err) = record(dbpath, e) {
+        log::error!("unable to write audit entry for {}: {err}", e.username);
+    }
+}
+
+/// count the objects a client sends in applyGraves,applyChanges and applyChunk
+fn count_objects(v: &serde_json::Value) -> i64 {
+    use serde_json::Value;
+    match v {
+        Value::Object(o) => o
+            .iter()
+            .map(|(k, v)| match (k.as_str(), v) {
+                // unchunked changes send decks and deck configs as a pair of lists
+                ("decks", Value::Array(a)) if a.iter().all(Value::is_array) => a
+                    .iter()
+                    .map(|e| e.as_array().map_or(0, Vec::len) as i64)
+                    .sum(),
+                ("cards" | "notes" | "decks" | "revlog" | "models" | "tags", Value::Array(a)) => {
+                    a.len() as i64
+                }
+                (_, Value::Object(_)) => count_objects(v),
+                _ => 0,
+            })
+            .sum(),
+        _ => 0,
+    }
+}
+
+/// number of objects carried by a sync request,0 for requests that do not change anything
+pub fn changes_in_request(method: &str, data: &[u8]) -> i64 {
+    match method {
+        "downloadFiles" => serde_json::from_slice::<serde_json::Value>(data)
+            .ok()
+            .and_then(|v| v.get("files").and_then(|f| f.as_array()).map(Vec::len))
+            .unwrap_or_default() as i64,
+        "applyGraves" | "applyChanges" | "applyChunk" => serde_json::from_slice(data)
+            .map(|v| count_objects(&v))
+            .unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+/// sessions without a request for this long are recorded as incomplete,
+/// i.e. a client that lost its connection in the middle of a sync
+const SESSION_TIMEOUT: i64 = 30 * 60;
+
+/// A sync spanning several requests,written to the audit table once it ends.
+struct Session {
+    entry: AuditEntry,
+    last_seen: i64,
+}
+
+impl Session {
+    fn new(entry: AuditEntry) -> Self {
+        let last_seen = entry.started;
+        Session { entry, last_seen }
+    }
+
+    fn close(mut self, result: &str, t: i64) -> AuditEntry {
+        self.entry.finished = Some(t);
+        self.entry.result = result.to_string();
+        self.entry
+    }
+}
+
+/// Tracks in-progress sync sessions from the requests seen by the sync middleware.
+pub struct AuditLog {
+    db: String,
+    /// opened on the first write and kept for the following ones
+    conn: Arc<Mutex<Option<Connection>>>,
+    /// (username,is media sync) -> session
+    sessions: Mutex<HashMap<(String, bool), Session>>,
+}
+
+impl AuditLog {
+    pub fn new(auth_db: &str) -> Self {
+        AuditLog {
+            db: auth_db.to_string(),
+            conn: Default::default(),
+            sessions: Default::default(),
+        }
+    }
+
+    /// write entries on the blocking thread pool,only logging failures
+    pub async fn record(&self, entries: Vec<AuditEntry>) {
+        if entries.is_empty() {
+            return;
+        }
+        let (db, conn) = (self.db.clone(), self.conn.clone());
+        let written = web::block(move || -> Result<(), rusqlite::Error> {
+            let mut conn = conn.lock().expect("audit connection lock");
+            if conn.is_none() {
+                let c = Connection::open(&db)?;
+                create_audit_table(&c)?;
+                *conn = Some(c);
+            }
+            let c = conn.as_mut().expect("audit connection");
+            let result = (|| {
+                let tx = c.transaction()?;
+                for e in &entries {
+                    insert(&tx, e)?;
+                }
+                tx.commit()
+            })();
+            if result.is_err() {
+                // reopen on the next write in case the database was replaced
+                *conn = None;
+            }
+            result
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => log::error!("unable to write audit entries: {err}"),
+            Err(err) => log::error!("unable to write audit entries: {err}"),
+        }
+    }
+
+    /// update sessions after a request was handled.
+    ///
+    /// `method` is the last segment of the request path and `changes` the result of
+    /// `changes_in_request` for its body.
+    pub async fn observe(
+        &self,
+        username: &str,
+        ip: Option<IpAddr>,
+        client_version: &str,
+        method: &str,
+        changes: i64,
+        success: bool,
+    ) {
+        let ended = self.update_sessions(
+            username,
+            ip,
+            client_version,
+            method,
+            changes,
+            success,
+            now(),
+        );
+        self.record(ended).await;
+    }
+
+    /// Record the sessions without a request for `SESSION_TIMEOUT` as incomplete,
+    /// every minute,so they show up without waiting for the next request of their user.
+    pub async fn watch_sessions(audit: web::Data<AuditLog>) {
+        let mut timer = actix_web::rt::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            timer.tick().await;
+            let ended = audit.expire(now());
+            audit.record(ended).await;
+        }
+    }
+
+    /// record the sessions still open when the server stops
+    pub async fn flush(&self) {
+        let t = now();
+        let ended: Vec<_> = {
+            let mut sessions = self.sessions.lock().expect("audit sessions lock");
+            sessions
+                .drain()
+                .map(|(_, s)| s.close("incomplete", t).detail("server stopped"))
+                .collect()
+        };
+        self.record(ended).await;
+    }
+
+    /// entries of the sessions without a request since `t - SESSION_TIMEOUT`
+    fn expire(&self, t: i64) -> Vec<AuditEntry> {
+        let mut sessions = self.sessions.lock().expect("audit sessions lock");
+        expire_sessions(&mut sessions, t)
+    }
+
+    /// entries of the sessions ended by a request handled at `t`
+    #[allow(clippy::too_many_arguments)]
+    fn update_sessions(
+        &self,
+        username: &str,
+        ip: Option<IpAddr>,
+        client_version: &str,
+        method: &str,
+        changes: i64,
+        success: bool,
+        t: i64,
+    ) -> Vec<AuditEntry> {
+        let entry = |kind: &str| AuditEntry {
+            username: username.to_string(),
+            ip: ip.map(|i| i.to_string()),
+            client_version: Some(client_version.to_string()).filter(|v| !v.is_empty()),
+            kind: kind.to_string(),
+            started: t,
+            result: "in progress".to_string(),
+            ..Default::default()
+        };
+        let result = if success { "ok" } else { "error" };
+        let media = matches!(
+            method,
+            "begin" | "mediaChanges" | "uploadChanges" | "downloadFiles" | "mediaSanity"
+        );
+        let key = (username.to_string(), media);
+        let mut sessions = self.sessions.lock().expect("audit sessions lock");
+        let mut ended = expire_sessions(&mut sessions, t);
+        match method {
+            "hostKey" => {
+                let mut e = entry("login");
+                e.finished = Some(e.started);
+                e.result = if success { "ok" } else { "failed" }.to_string();
+                ended.push(e);
+            }
+            "start" | "begin" => {
+                if let Some(old) = sessions.remove(&key) {
+                    ended.push(old.close("incomplete", t));
+                }
+                let session = Session::new(entry(if media { "media" } else { "normal" }));
+                if success {
+                    sessions.insert(key, session);
+                } else {
+                    ended.push(session.close(result, t));
+                }
+            }
+            "upload" | "download" => {
+                if let Some(old) = sessions.remove(&key) {
+                    ended.push(old.close("incomplete", t));
+                }
+                let kind = if method == "upload" {
+                    "full_upload"
+                } else {
+                    "full_download"
+                };
+                ended.push(Session::new(entry(kind)).close(result, t));
+            }
+            "finish" | "abort" | "mediaSanity" => {
+                if let Some(session) = sessions.remove(&key) {
+                    let result = match (method, success) {
+                        ("abort", true) => "aborted",
+                        _ => result,
+                    };
+                    ended.push(session.close(result, t));
+                }
+            }
+            _ => {
+                let Some(session) = sessions.get_mut(&key) else {
+                    return ended;
+                };
+                *session.entry.changes.get_or_insert(0) += changes;
+                session.last_seen = t;
+                if !success {
+                    if let Some(session) = sessions.remove(&key) {
+                        ended.push(session.close(result, t));
+                    }
+                }
+            }
+        }
+        ended
+    }
+}
+
+/// remove the sessions without a request for `SESSION_TIMEOUT`,closed as incomplete
+fn expire_sessions(sessions: &mut HashMap<(String, bool), Session>, t: i64) -> Vec<AuditEntry> {
+    let stale: Vec<_> = sessions
+        .iter()
+        .filter(|(_, s)| t - s.last_seen > SESSION_TIMEOUT)
+        .map(|(k, _)| k.clone())
+        .collect();
+    stale
+        .into_iter()
+        .filter_map(|k| sessions.remove(&k))
+        .map(|s| s.close("incomplete", t))
+        .collect()
+}
+
+/// parse durations such as `30m`,`12h`,`7d` or `2w` into seconds
+fn parse_since(s: &str) -> Result<i64, ApplicationError> {
+    let s = s.trim();
+    let (n, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let n: i64 = n
+        .parse()
+        .map_err(|_| ApplicationError::ValueNotFound(format!("invalid duration: {s}")))?;
+    let mult = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => {
+            return Err(ApplicationError::ValueNotFound(format!(
+                "invalid duration unit in {s},use one of s,m,h,d,w"
+            )))
+        }
+    };
+    Ok(n * mult)
+}
+
+/// command-line audit query,i.e. ankisyncd audit --user alice --since 7d
+pub fn print_audit<P: AsRef<Path>>(
+    dbpath: P,
+    user: Option<&str>,
+    since: Option<&str>,
+) -> Result<(), ApplicationError> {
+    let since = match since {
+        Some(s) => now() - parse_since(s)?,
+        None => 0,
+    };
+    let conn = Connection::open(dbpath)?;
+    create_audit_table(&conn)?;
+    let sql = "SELECT datetime(started,'unixepoch','localtime'),
+    ifnull(finished-started,''),username,kind,result,ifnull(changes,''),
+    ifnull(ip,''),ifnull(client_version,''),ifnull(detail,'')
+FROM audit WHERE (?1 IS NULL OR username=?1) AND started>=?2 ORDER BY started,id";
+    let mut stmt = conn.prepare(sql)?;
+    let rows = stmt.query_map(params![user, since], |r| {
+        let mut cols = vec![];
+        for i in 0..9 {
+            cols.push(r.get::<_, rusqlite::types::Value>(i).map(|v| match v {
+                rusqlite::types::Value::Text(s) => s,
+                rusqlite::types::Value::Integer(i) => i.to_string(),
+                _ => String::new(),
+            })?);
+        }
+        Ok(cols)
+    })?;
+    println!("started\tsecs\tuser\tkind\tresult\tchanges\tip\tclient\tdetail");
+    for row in rows {
+        println!("{}", row?.join("\t"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const T: i64 = 1_700_000_000;
+
+    fn observe(log: &AuditLog, method: &str, changes: i64, t: i64) -> Vec<AuditEntry> {
+        log.update_sessions("alice", None, "anki,2.1.66", method, changes, true, t)
+    }
+
+    #[test]
+    fn session_merges_requests_until_finish() {
+        let log = AuditLog::new("unused.db");
+        assert!(observe(&log, "start", 0, T).is_empty());
+        assert!(observe(&log, "applyChanges", 3, T + 1).is_empty());
+        assert!(observe(&log, "applyChunk", 2, T + 2).is_empty());
+        let ended = observe(&log, "finish", 0, T + 3);
+        assert_eq!(ended.len(), 1);
+        let e = &ended[0];
+        assert_eq!((e.kind.as_str(), e.result.as_str()), ("normal", "ok"));
+        assert_eq!(
+            (e.started, e.finished, e.changes),
+            (T, Some(T + 3), Some(5))
+        );
+        assert_eq!(e.client_version.as_deref(), Some("anki,2.1.66"));
+        assert!(log.expire(T + 10 * SESSION_TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn media_and_normal_sessions_are_separate() {
+        let log = AuditLog::new("unused.db");
+        observe(&log, "start", 0, T);
+        observe(&log, "begin", 0, T);
+        let ended = observe(&log, "mediaSanity", 0, T + 1);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].kind, "media");
+        let ended = observe(&log, "finish", 0, T + 2);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].kind, "normal");
+    }
+
+    #[test]
+    fn restarted_session_closes_the_previous_one() {
+        let log = AuditLog::new("unused.db");
+        observe(&log, "start", 0, T);
+        let ended = observe(&log, "start", 0, T + 5);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(
+            (ended[0].result.as_str(), ended[0].started),
+            ("incomplete", T)
+        );
+        let ended = observe(&log, "abort", 0, T + 6);
+        assert_eq!(ended[0].result, "aborted");
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let log = AuditLog::new("unused.db");
+        observe(&log, "start", 0, T);
+        observe(&log, "applyChanges", 1, T + 60);
+        assert!(log.expire(T + 60 + SESSION_TIMEOUT).is_empty());
+        let ended = log.expire(T + 61 + SESSION_TIMEOUT);
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].result, "incomplete");
+        assert_eq!(ended[0].finished, Some(T + 61 + SESSION_TIMEOUT));
+        // a later request of another user also closes it
+        observe(&log, "start", 0, T);
+        let ended = log.update_sessions(
+            "bob",
+            None,
+            "",
+            "hostKey",
+            0,
+            false,
+            T + 2 * SESSION_TIMEOUT,
+        );
+        let results: Vec<_> = ended
+            .iter()
+            .map(|e| (e.username.as_str(), e.result.as_str()))
+            .collect();
+        assert_eq!(results, [("alice", "incomplete"), ("bob", "failed")]);
+    }
+
+    #[actix_web::test]
+    async fn flush_records_open_sessions() {
+        let dir =
+            std::env::temp_dir().join(format!("ankisyncd-audit-{:016x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = dir.join("auth.db");
+        let log = AuditLog::new(db.to_str().unwrap());
+        observe(&log, "start", 0, T);
+        log.flush().await;
+        let conn = Connection::open(&db).unwrap();
+        let (user, result, detail): (String, String, String) = conn
+            .query_row("SELECT username,result,detail FROM audit", [], |r| {
+                Ok((r.get(0)?, r.get(1)?, r.get(2)?))
+            })
+            .unwrap();
+        assert_eq!(
+            (user.as_str(), result.as_str(), detail.as_str()),
+            ("alice", "incomplete", "server stopped")
+        );
+        assert!(log.sessions.lock().unwrap().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_since("90").unwrap(), 90);
+        assert_eq!(parse_since("30m").unwrap(), 1800);
+        assert_eq!(parse_since(" 12h ").unwrap(), 12 * 3600);
+        assert_eq!(parse_since("7d").unwrap(), 7 * 86400);
+        assert_eq!(parse_since("2w").unwrap(), 14 * 86400);
+        for bad in ["", "d", "7y", "-1d", "1.5h"] {
+            assert!(parse_since(bad).is_err(), "{bad}");
+        }
+    }
+}
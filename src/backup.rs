@@ -0,0 +1,696 @@
+// snapshots of the collections,kept compressed in collections/<user>/backups/
+use crate::archive::TarWriter;
+use crate::config::ConfigBackup;
+use crate::error::ApplicationError;
+use crate::lock::{SyncPause, LOCK_FILE};
+use actix_web::web;
+use anki::sync::http_server::SimpleServer;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, TimeZone, Utc};
+use rusqlite::{Connection, DatabaseName};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+const SNAPSHOT_DIR: &str = "backups";
+const SNAPSHOT_EXT: &str = ".anki2.zst";
+const TIME_FORMAT: &str = "%Y%m%d-%H%M%S%3f";
+/// snapshots taken by older versions have a resolution of one second
+const SECONDS_TIME_FORMAT: &str = "%Y%m%d-%H%M%S";
+const COLLECTION_FILE: &str = "collection.anki2";
+/// folder of the user folders in the root dir
+const COLLECTIONS_DIR: &str = "collections";
+
+/// why a snapshot was taken,part of its file name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotReason {
+    /// before a full upload replaced the collection
+    Upload,
+    /// after a normal sync,at most once per `interval_hours`
+    Periodic,
+    /// of the collection replaced by a restore
+    Restore,
+    /// before `ankisyncd check --repair` modifies the collection
+    Repair,
+    /// before `ankisyncd import` adds a package to the collection
+    Import,
+}
+
+impl SnapshotReason {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SnapshotReason::Upload => "upload",
+            SnapshotReason::Periodic => "periodic",
+            SnapshotReason::Restore => "restore",
+            SnapshotReason::Repair => "repair",
+            SnapshotReason::Import => "import",
+        }
+    }
+}
+
+/// A compressed copy of `collection.anki2`,named `<time>-<reason>.anki2.zst`.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// file name without extension,such as 20240131-120000250-upload
+    pub id: String,
+    pub created: DateTime<Utc>,
+    pub reason: String,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+impl Snapshot {
+    fn from_path(path: PathBuf) -> Option<Self> {
+        let name = path.file_name()?.to_str()?;
+        let id = name.strip_suffix(SNAPSHOT_EXT)?.to_string();
+        // the time has the form YYYYMMDD-HHMMSSmmm,or YYYYMMDD-HHMMSS for older snapshots
+        let (time, reason) = id.rsplit_once('-')?;
+        let format = if time.len() == 15 {
+            SECONDS_TIME_FORMAT
+        } else {
+            TIME_FORMAT
+        };
+        let created = Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(time, format).ok()?);
+        let size = fs::metadata(&path).ok()?.len();
+        Some(Snapshot {
+            reason: reason.to_string(),
+            id,
+            created,
+            path,
+            size,
+        })
+    }
+}
+
+pub fn snapshot_dir(user_folder: &Path) -> PathBuf {
+    user_folder.join(SNAPSHOT_DIR)
+}
+
+/// snapshots of a user,newest first
+pub fn list_snapshots(user_folder: &Path) -> Result<Vec<Snapshot>, ApplicationError> {
+    let dir = snapshot_dir(user_folder);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut snapshots = vec![];
+    for entry in fs::read_dir(dir)? {
+        if let Some(s) = Snapshot::from_path(entry?.path()) {
+            snapshots.push(s);
+        }
+    }
+    snapshots.sort_by(|a, b| b.created.cmp(&a.created).then(b.id.cmp(&a.id)));
+    Ok(snapshots)
+}
+
+/// Copy the collection with the sqlite backup api,which is consistent even while a sync
+/// writes to it,and compress the copy.Nothing is done if the user has no collection yet.
+pub fn take_snapshot(
+    user_folder: &Path,
+    reason: SnapshotReason,
+) -> Result<Option<Snapshot>, ApplicationError> {
+    let col = user_folder.join(COLLECTION_FILE);
+    if !col.exists() {
+        return Ok(None);
+    }
+    let dir = snapshot_dir(user_folder);
+    fs::create_dir_all(&dir)?;
+    // creating the partial file reserves the id,wait for the next millisecond if it is taken
+    let (id, compressed, file) = loop {
+        let id = format!("{}-{}", Utc::now().format(TIME_FORMAT), reason.as_str());
+        let compressed = dir.join(format!(".{id}{SNAPSHOT_EXT}"));
+        if !dir.join(format!("{id}{SNAPSHOT_EXT}")).exists() {
+            match File::options()
+                .write(true)
+                .create_new(true)
+                .open(&compressed)
+            {
+                Ok(file) => break (id, compressed, file),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    };
+    let copy = dir.join(format!(".{id}.anki2"));
+    let result = (|| -> Result<(), ApplicationError> {
+        Connection::open(&col)?.backup(DatabaseName::Main, &copy, None)?;
+        zstd::stream::copy_encode(File::open(&copy)?, file, 0)?;
+        Ok(())
+    })();
+    let _ = fs::remove_file(&copy);
+    if let Err(e) = result {
+        let _ = fs::remove_file(&compressed);
+        return Err(e);
+    }
+    // the snapshot only shows up once complete
+    let path = dir.join(format!("{id}{SNAPSHOT_EXT}"));
+    fs::rename(&compressed, &path)?;
+    Ok(Snapshot::from_path(path))
+}
+
+fn find_snapshot(user_folder: &Path, id: &str) -> Result<Snapshot, ApplicationError> {
+    list_snapshots(user_folder)?
+        .into_iter()
+        .find(|s| s.id == id)
+        .ok_or_else(|| ApplicationError::NotFound(format!("no snapshot {id}")))
+}
+
+/// Decompress a snapshot next to the collection,returning the temporary file.
+///
+/// Its schema modification time is bumped,so clients are asked for a full sync
+/// instead of merging their changes into the restored collection.
+fn prepare_restore(user_folder: &Path, snapshot: &Snapshot) -> Result<PathBuf, ApplicationError> {
+    let tmp = user_folder.join(".restore.anki2");
+    let result = (|| -> Result<(), ApplicationError> {
+        zstd::stream::copy_decode(File::open(&snapshot.path)?, File::create(&tmp)?)?;
+        let now = Utc::now().timestamp_millis();
+        Connection::open(&tmp)?.execute("UPDATE col SET mod=?1,scm=?1", [now])?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(tmp)
+}
+
+/// put the prepared collection in place,the current one must be closed
+fn replace_collection(user_folder: &Path, prepared: &Path) -> Result<(), ApplicationError> {
+    let col = user_folder.join(COLLECTION_FILE);
+    // the journal of the old collection must not be applied to the restored one
+    for suffix in ["-wal", "-shm", "-journal"] {
+        let path = user_folder.join(format!("{COLLECTION_FILE}{suffix}"));
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    fs::rename(prepared, col)?;
+    Ok(())
+}
+
+/// Restore snapshot `id` while the server is stopped.
+///
+/// A running server keeps the collection open,`restore_in_server` closes it first.
+/// The current collection is saved as a snapshot first.
+pub fn restore_snapshot(user_folder: &Path, id: &str) -> Result<Snapshot, ApplicationError> {
+    let snapshot = find_snapshot(user_folder, id)?;
+    take_snapshot(user_folder, SnapshotReason::Restore)?;
+    let prepared = prepare_restore(user_folder, &snapshot)?;
+    if let Err(e) = replace_collection(user_folder, &prepared) {
+        let _ = fs::remove_file(&prepared);
+        return Err(e);
+    }
+    Ok(snapshot)
+}
+
+fn ensure_not_syncing(server: &SimpleServer, username: &str) -> Result<(), ApplicationError> {
+    let state = server.state.lock().expect("server state lock");
+    if state
+        .users
+        .values()
+        .any(|u| u.name == username && u.sync_state.is_some())
+    {
+        return Err(ApplicationError::Conflict(format!(
+            "{username} is syncing,try again once the sync is over"
+        )));
+    }
+    Ok(())
+}
+
+/// Restore snapshot `id` of `username` while the server runs.
+///
+/// Refused while the user syncs,the open collection is closed before being replaced.
+pub fn restore_in_server(
+    server: &SimpleServer,
+    user_folder: &Path,
+    username: &str,
+    id: &str,
+) -> Result<Snapshot, ApplicationError> {
+    ensure_not_syncing(server, username)?;
+    let snapshot = find_snapshot(user_folder, id)?;
+    take_snapshot(user_folder, SnapshotReason::Restore)?;
+    let prepared = prepare_restore(user_folder, &snapshot)?;
+    // a sync may have started in the meantime,check again with the lock held until the end
+    let result = (|| -> Result<(), ApplicationError> {
+        let mut state = server.state.lock().expect("server state lock");
+        for user in state.users.values_mut().filter(|u| u.name == username) {
+            if user.sync_state.is_some() {
+                return Err(ApplicationError::Conflict(format!(
+                    "{username} is syncing,try again once the sync is over"
+                )));
+            }
+            if let Some(col) = user.col.take() {
+                col.close(None)?;
+            }
+        }
+        replace_collection(user_folder, &prepared)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&prepared);
+    }
+    result.map(|_| snapshot)
+}
+
+/// Which snapshots the retention policy keeps,`snapshots` being newest first.
+///
+/// Days and weeks,starting on monday,are those of `tz`,the local time zone of the server
+/// like the times shown by `backup --list`.
+fn retained<Tz: TimeZone>(
+    snapshots: &[Snapshot],
+    config: &ConfigBackup,
+    now: DateTime<Utc>,
+    tz: &Tz,
+) -> Vec<bool> {
+    let mut keep = vec![false; snapshots.len()];
+    for k in keep.iter_mut().take(config.keep_last) {
+        *k = true;
+    }
+    // the newest snapshot of each of the last keep_daily days and keep_weekly weeks
+    for (period, count) in [(1, config.keep_daily), (7, config.keep_weekly)] {
+        // first day of the day or week holding `t`
+        let bucket = |t: &DateTime<Utc>| {
+            let day = t.with_timezone(tz).date_naive();
+            match period {
+                7 => day - Duration::days(day.weekday().num_days_from_monday().into()),
+                _ => day,
+            }
+        };
+        let oldest = bucket(&now) - Duration::days(period * (count - 1));
+        let mut seen = HashSet::new();
+        for (i, s) in snapshots.iter().enumerate() {
+            let b = bucket(&s.created);
+            if b < oldest {
+                break;
+            }
+            if seen.insert(b) {
+                keep[i] = true;
+            }
+        }
+    }
+    keep
+}
+
+/// delete the snapshots not kept by the retention policy,returning how many were deleted
+pub fn prune(user_folder: &Path, config: &ConfigBackup) -> Result<usize, ApplicationError> {
+    let snapshots = list_snapshots(user_folder)?;
+    let keep = retained(&snapshots, config, Utc::now(), &Local);
+    let mut deleted = 0;
+    for (s, keep) in snapshots.iter().zip(keep) {
+        if !keep {
+            fs::remove_file(&s.path)?;
+            deleted += 1;
+        }
+    }
+    Ok(deleted)
+}
+
+/// name and folder of the user owning `sync_key`
+pub(crate) fn user_of(server: &SimpleServer, sync_key: &str) -> Option<(String, PathBuf)> {
+    let state = server.state.lock().expect("server state lock");
+    state
+        .users
+        .get(sync_key)
+        .map(|u| (u.name.clone(), u.folder.clone()))
+}
+
+async fn snapshot_and_prune(
+    username: String,
+    folder: PathBuf,
+    reason: SnapshotReason,
+    config: ConfigBackup,
+) {
+    let result = web::block(move || -> Result<_, ApplicationError> {
+        let snapshot = take_snapshot(&folder, reason)?;
+        let deleted = prune(&folder, &config)?;
+        Ok((snapshot, deleted))
+    })
+    .await;
+    match result {
+        Ok(Ok((Some(s), deleted))) => log::info!(
+            "saved snapshot {} of {username} ({} bytes),{deleted} old snapshot(s) deleted",
+            s.id,
+            s.size
+        ),
+        Ok(Ok((None, _))) => {}
+        Ok(Err(e)) => log::error!("unable to snapshot the collection of {username}: {e}"),
+        Err(e) => log::error!("unable to snapshot the collection of {username}: {e}"),
+    }
+}
+
+/// Snapshot the collection a full upload is about to replace.
+///
+/// The upload goes on if the snapshot fails,the error is logged.
+pub async fn before_full_upload(server: &SimpleServer, sync_key: &str, config: ConfigBackup) {
+    if !config.enabled {
+        return;
+    }
+    if let Some((username, folder)) = user_of(server, sync_key) {
+        snapshot_and_prune(username, folder, SnapshotReason::Upload, config).await;
+    }
+}
+
+/// Snapshot the collection after a normal sync if the newest snapshot is older than the interval.
+pub async fn after_sync(server: &SimpleServer, sync_key: &str, config: ConfigBackup) {
+    if !config.enabled || config.interval_hours == 0 {
+        return;
+    }
+    let Some((username, folder)) = user_of(server, sync_key) else {
+        return;
+    };
+    let interval = Duration::hours(config.interval_hours as i64);
+    let due = match list_snapshots(&folder) {
+        Ok(s) => match s.first() {
+            Some(newest) => Utc::now() - newest.created >= interval,
+            None => true,
+        },
+        Err(e) => {
+            log::error!("unable to list the snapshots of {username}: {e}");
+            false
+        }
+    };
+    if due {
+        snapshot_and_prune(username, folder, SnapshotReason::Periodic, config).await;
+    }
+}
+
+/// what went into a backup archive
+#[derive(Debug, Default)]
+pub struct ArchiveSummary {
+    pub users: usize,
+    pub databases: usize,
+    pub files: usize,
+}
+
+const SQLITE_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+fn is_sqlite(path: &Path) -> bool {
+    let mut header = [0u8; 16];
+    File::open(path)
+        .and_then(|mut f| std::io::Read::read_exact(&mut f, &mut header))
+        .is_ok()
+        && &header == SQLITE_HEADER
+}
+
+/// hidden temporary files and sqlite journals,left out of archives
+fn skipped(name: &str) -> bool {
+    name.starts_with('.')
+        || ["-wal", "-shm", "-journal"]
+            .iter()
+            .any(|s| name.ends_with(s))
+}
+
+/// Add `path` as `archived`,folders with their content and sqlite databases through
+/// a consistent copy made with the online backup api.
+fn archive_path<W: std::io::Write>(
+    tar: &mut TarWriter<W>,
+    path: &Path,
+    archived: &str,
+    tmp: &Path,
+    summary: &mut ArchiveSummary,
+) -> Result<(), ApplicationError> {
+    let file_type = fs::symlink_metadata(path)?.file_type();
+    if file_type.is_dir() {
+        let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !skipped(&name) {
+                archive_path(
+                    tar,
+                    &entry.path(),
+                    &format!("{archived}/{name}"),
+                    tmp,
+                    summary,
+                )?;
+            }
+        }
+    } else if !file_type.is_file() {
+        // sockets and links
+    } else if is_sqlite(path) {
+        Connection::open(path)?.backup(DatabaseName::Main, tmp, None)?;
+        tar.append_file(archived, tmp)?;
+        fs::remove_file(tmp)?;
+        summary.databases += 1;
+    } else {
+        tar.append_file(archived, path)?;
+        summary.files += 1;
+    }
+    Ok(())
+}
+
+/// Write everything under `root_dir` to a zstd compressed tar archive at `out`:`auth.db`,
+/// the folders of every user with their collection,media database and media files,
+/// the shared decks and the media store.
+///
+/// Hidden temporary files and the lock of the server are left out.New sync sessions
+/// of a user are refused while their folder is copied.
+pub fn archive_all(root_dir: &Path, out: &Path) -> Result<ArchiveSummary, ApplicationError> {
+    let name = out
+        .file_name()
+        .ok_or_else(|| ApplicationError::ParseConfig(format!("invalid output {}", out.display())))?
+        .to_string_lossy()
+        .to_string();
+    let dir = out.parent().filter(|d| !d.as_os_str().is_empty());
+    let dir = dir.unwrap_or_else(|| Path::new("."));
+    let partial = dir.join(format!(".{name}.partial"));
+    let tmp = dir.join(format!(".{name}.db"));
+    let result = (|| -> Result<ArchiveSummary, ApplicationError> {
+        let mut encoder = zstd::stream::Encoder::new(File::create(&partial)?, 0)?;
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        encoder.multithread(threads as u32)?;
+        let mut tar = TarWriter::new(encoder);
+        let mut summary = ArchiveSummary::default();
+
+        let mut entries = fs::read_dir(root_dir)?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if skipped(&name) || name == LOCK_FILE {
+                continue;
+            }
+            let path = entry.path();
+            if name != COLLECTIONS_DIR || !path.is_dir() {
+                archive_path(&mut tar, &path, &name, &tmp, &mut summary)?;
+                continue;
+            }
+            let mut users = fs::read_dir(&path)?.collect::<Result<Vec<_>, _>>()?;
+            users.sort_by_key(|e| e.file_name());
+            for user in users.iter().filter(|e| e.path().is_dir()) {
+                let username = user.file_name().to_string_lossy().to_string();
+                let _pause = SyncPause::new(&user.path())?;
+                archive_path(
+                    &mut tar,
+                    &user.path(),
+                    &format!("{COLLECTIONS_DIR}/{username}"),
+                    &tmp,
+                    &mut summary,
+                )?;
+                summary.users += 1;
+            }
+        }
+        tar.finish()?.finish()?.sync_all()?;
+        Ok(summary)
+    })();
+    let _ = fs::remove_file(&tmp);
+    match result {
+        Ok(summary) => {
+            fs::rename(&partial, out)?;
+            Ok(summary)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    fn snapshots(times: &[DateTime<Utc>]) -> Vec<Snapshot> {
+        times
+            .iter()
+            .map(|&created| Snapshot {
+                id: format!("{}-upload", created.format(TIME_FORMAT)),
+                created,
+                reason: "upload".to_string(),
+                path: PathBuf::new(),
+                size: 0,
+            })
+            .collect()
+    }
+
+    fn policy(keep_last: usize, keep_daily: i64, keep_weekly: i64) -> ConfigBackup {
+        ConfigBackup {
+            keep_last,
+            keep_daily,
+            keep_weekly,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keeps_the_newest_of_each_day() {
+        // wednesday 2024-01-31
+        let now = at(2024, 1, 31, 12, 0);
+        let s = snapshots(&[
+            at(2024, 1, 31, 10, 0),
+            at(2024, 1, 31, 8, 0),
+            at(2024, 1, 30, 23, 59),
+            at(2024, 1, 30, 0, 0),
+            at(2024, 1, 29, 0, 0),
+            at(2024, 1, 28, 23, 59),
+        ]);
+        let keep = retained(&s, &policy(0, 3, 0), now, &Utc);
+        assert_eq!(keep, [true, false, true, false, true, false]);
+        // the window is in whole days,not 72 hours back from now
+        let keep = retained(&s, &policy(0, 3, 0), at(2024, 1, 31, 23, 59), &Utc);
+        assert_eq!(keep, [true, false, true, false, true, false]);
+        assert_eq!(retained(&s, &policy(0, 0, 0), now, &Utc), [false; 6]);
+    }
+
+    #[test]
+    fn keeps_the_newest_of_each_week() {
+        let now = at(2024, 1, 31, 12, 0);
+        let s = snapshots(&[
+            // monday of the current week
+            at(2024, 1, 29, 0, 0),
+            // sunday,the week before
+            at(2024, 1, 28, 23, 59),
+            at(2024, 1, 22, 0, 0),
+            // outside the last 2 weeks
+            at(2024, 1, 21, 23, 59),
+        ]);
+        let keep = retained(&s, &policy(0, 0, 2), now, &Utc);
+        assert_eq!(keep, [true, true, false, false]);
+        let keep = retained(&s, &policy(0, 0, 3), now, &Utc);
+        assert_eq!(keep, [true, true, false, true]);
+    }
+
+    #[test]
+    fn keep_last_and_buckets_add_up() {
+        let now = at(2024, 1, 31, 12, 0);
+        let s = snapshots(&[
+            at(2024, 1, 31, 11, 0),
+            at(2024, 1, 31, 10, 0),
+            at(2024, 1, 31, 9, 0),
+            at(2024, 1, 1, 0, 0),
+        ]);
+        assert_eq!(
+            retained(&s, &policy(2, 0, 0), now, &Utc),
+            [true, true, false, false]
+        );
+        assert_eq!(
+            retained(&s, &policy(2, 7, 4), now, &Utc),
+            [true, true, false, false]
+        );
+    }
+
+    /// files under `dir`,relative to it
+    fn files(dir: &Path, prefix: &str, found: &mut Vec<(String, Vec<u8>)>) {
+        let mut entries: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap()).collect();
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let name = format!("{prefix}{}", entry.file_name().to_string_lossy());
+            if entry.file_type().unwrap().is_dir() {
+                files(&entry.path(), &format!("{name}/"), found);
+            } else {
+                found.push((name, fs::read(entry.path()).unwrap()));
+            }
+        }
+    }
+
+    #[test]
+    fn archive_restores_the_whole_root() {
+        let dir =
+            std::env::temp_dir().join(format!("ankisyncd-archive-{:016x}", rand::random::<u64>()));
+        let root = dir.join("root");
+        let user = root.join(COLLECTIONS_DIR).join("alice");
+        fs::create_dir_all(user.join("collection.media")).unwrap();
+        for db in [root.join("auth.db"), user.join(COLLECTION_FILE)] {
+            let conn = Connection::open(db).unwrap();
+            conn.execute_batch("CREATE TABLE t (v TEXT); INSERT INTO t VALUES ('kept');")
+                .unwrap();
+        }
+        let kept = [
+            ("collections/alice/collection.media/a.jpg", b"jpg".to_vec()),
+            ("media-store/ab/abcdef", b"stored".to_vec()),
+            ("shared-decks/Biology 101/1.apkg", b"apkg".to_vec()),
+        ];
+        let left_out = [
+            LOCK_FILE,
+            ".publish-0123456789abcdef.apkg",
+            "collections/alice/collection.anki2-wal",
+            "collections/alice/.restore.anki2",
+        ];
+        for (name, content) in &kept {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        for name in left_out {
+            fs::write(root.join(name), b"tmp").unwrap();
+        }
+
+        let out = dir.join("full.tar.zst");
+        let summary = archive_all(&root, &out).unwrap();
+        assert_eq!((summary.users, summary.databases, summary.files), (1, 2, 3));
+        let restored = dir.join("restored");
+        let decoder = zstd::stream::Decoder::new(File::open(&out).unwrap()).unwrap();
+        ::tar::Archive::new(decoder).unpack(&restored).unwrap();
+
+        let mut found = vec![];
+        files(&restored, "", &mut found);
+        let names: Vec<_> = found.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "auth.db",
+                "collections/alice/collection.anki2",
+                "collections/alice/collection.media/a.jpg",
+                "media-store/ab/abcdef",
+                "shared-decks/Biology 101/1.apkg",
+            ]
+        );
+        for (name, content) in &kept {
+            assert_eq!(&fs::read(restored.join(name)).unwrap(), content);
+        }
+        for db in ["auth.db", "collections/alice/collection.anki2"] {
+            let v: String = Connection::open(restored.join(db))
+                .unwrap()
+                .query_row("SELECT v FROM t", [], |r| r.get(0))
+                .unwrap();
+            assert_eq!(v, "kept");
+        }
+        // no partial archive or copy is left next to the output
+        let mut outputs: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        outputs.sort();
+        assert_eq!(outputs, ["full.tar.zst", "restored", "root"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn days_follow_the_time_zone() {
+        let tokyo = FixedOffset::east_opt(9 * 3600).unwrap();
+        // 2024-01-31 09:00 in tokyo
+        let now = at(2024, 1, 31, 0, 0);
+        let s = snapshots(&[
+            // 2024-01-31 05:00 in tokyo
+            at(2024, 1, 30, 20, 0),
+            // 2024-01-30 19:00 in tokyo
+            at(2024, 1, 30, 10, 0),
+        ]);
+        assert_eq!(retained(&s, &policy(0, 1, 0), now, &tokyo), [true, false]);
+        assert_eq!(retained(&s, &policy(0, 1, 0), now, &Utc), [false, false]);
+        assert_eq!(retained(&s, &policy(0, 2, 0), now, &Utc), [true, false]);
+        assert_eq!(retained(&s, &policy(0, 2, 0), now, &tokyo), [true, true]);
+    }
+}
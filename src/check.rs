@@ -0,0 +1,86 @@
+// offline integrity check of a user's collection,i.e. ankisyncd check --user alice [--repair]
+use crate::backup::{take_snapshot, SnapshotReason};
+use crate::error::ApplicationError;
+use anki::collection::CollectionBuilder;
+use rusqlite::{Connection, DatabaseName};
+use std::path::Path;
+
+const COLLECTION_FILE: &str = "collection.anki2";
+
+/// what `check_collection` found
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    /// messages of `PRAGMA integrity_check`,`["ok"]` for a sound database
+    pub integrity: Vec<String>,
+    /// (table,rows)
+    pub counts: Vec<(&'static str, i64)>,
+    /// problems found by the check database of anki,fixed when repairing
+    pub problems: Vec<String>,
+    /// id of the snapshot taken before repairing
+    pub snapshot: Option<String>,
+}
+
+fn integrity_check(conn: &Connection) -> Result<Vec<String>, ApplicationError> {
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let rows = stmt.query_map([], |r| r.get::<_, String>(0))?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+fn counts(conn: &Connection) -> Vec<(&'static str, i64)> {
+    ["notes", "cards", "revlog", "decks", "notetypes"]
+        .into_iter()
+        .filter_map(|table| {
+            conn.query_row(&format!("SELECT count() FROM {table}"), [], |r| r.get(0))
+                .ok()
+                .map(|n| (table, n))
+        })
+        .collect()
+}
+
+/// Run anki's check database on the collection at `path`,which it may modify.
+///
+/// The collection is opened as the server's,like for an import,so the fixed objects
+/// get the current usn and clients pull them with a normal sync.
+fn check_database(path: &Path) -> Result<Vec<String>, ApplicationError> {
+    let mut col = CollectionBuilder::new(path).set_server(true).build()?;
+    let output = col.check_database()?;
+    let problems = output.to_i18n_strings(&col.tr);
+    col.close(None)?;
+    Ok(problems)
+}
+
+/// Check the collection of the user in `user_folder`.
+///
+/// Without `repair`,anki's check runs on a temporary copy and the collection is left untouched.
+/// With `repair`,a snapshot is taken and the fixes are written to the collection,
+/// the server must not be running.
+pub fn check_collection(user_folder: &Path, repair: bool) -> Result<CheckReport, ApplicationError> {
+    let path = user_folder.join(COLLECTION_FILE);
+    if !path.exists() {
+        return Err(ApplicationError::NotFound(format!(
+            "no collection in {}",
+            user_folder.display()
+        )));
+    }
+    let mut report = CheckReport::default();
+    {
+        let conn = Connection::open(&path)?;
+        report.integrity = integrity_check(&conn)?;
+        report.counts = counts(&conn);
+    }
+    if repair {
+        report.snapshot = take_snapshot(user_folder, SnapshotReason::Repair)?.map(|s| s.id);
+        report.problems = check_database(&path)?;
+    } else {
+        let copy = user_folder.join(".check.anki2");
+        let result = Connection::open(&path)?
+            .backup(DatabaseName::Main, &copy, None)
+            .map_err(ApplicationError::from)
+            .and_then(|_| check_database(&copy));
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", copy.display()));
+        }
+        report.problems = result?;
+    }
+    Ok(report)
+}
@@ -1,44 +1,238 @@
 use crate::error::ApplicationError;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// env var naming the config file to use when `--config` is not given
+pub const CONFIG_ENV: &str = "ANKISYNCD_CONFIG";
+const CONFIG_FILE_NAME: &str = "ankisyncd.toml";
+
+/// Places searched for the config file when `--config` is not given,in order:
+/// `$ANKISYNCD_CONFIG`,the XDG config dir,`/etc/ankisyncd` and the directory of the binary.
+pub fn config_search_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+    if let Some(p) = std::env::var_os(CONFIG_ENV) {
+        paths.push(PathBuf::from(p));
+    }
+    if let Some(dir) = dirs::config_dir() {
+        paths.push(dir.join("ankisyncd").join(CONFIG_FILE_NAME));
+    }
+    #[cfg(unix)]
+    paths.push(PathBuf::from("/etc/ankisyncd").join(CONFIG_FILE_NAME));
+    if let Some(dir) = std::env::current_exe()
+        .ok()
+        .and_then(|p| p.parent().map(|d| d.to_owned()))
+    {
+        paths.push(dir.join(CONFIG_FILE_NAME));
+    }
+    paths
+}
+
+/// First existing config file of [`config_search_paths`].
+///
+/// `$ANKISYNCD_CONFIG` is returned even if missing,so that a wrong path is reported.
+pub fn find_config_file() -> Option<PathBuf> {
+    if let Some(p) = std::env::var_os(CONFIG_ENV) {
+        return Some(PathBuf::from(p));
+    }
+    config_search_paths().into_iter().find(|p| p.is_file())
+}
+
+/// prefix of the env vars overriding config keys,i.e. ANKISYNCD__LISTEN__PORT=8080
+const ENV_PREFIX: &str = "ANKISYNCD__";
+
+/// A `section.key=value` setting taking precedence over the config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigOverride {
+    key: Vec<String>,
+    value: String,
+}
+
+impl ConfigOverride {
+    /// `key` is dotted,such as `listen.port`
+    pub fn new(key: &str, value: impl ToString) -> Self {
+        ConfigOverride {
+            key: key.split('.').map(|k| k.to_string()).collect(),
+            value: value.to_string(),
+        }
+    }
+
+    /// parse `section.key=value`
+    pub fn parse(s: &str) -> Result<Self, ApplicationError> {
+        match s.split_once('=') {
+            Some((k, v)) if !k.trim().is_empty() => Ok(ConfigOverride::new(k.trim(), v)),
+            _ => Err(ApplicationError::ParseConfig(format!(
+                "invalid setting {s},expected section.key=value"
+            ))),
+        }
+    }
+
+    /// overrides from ANKISYNCD__SECTION__KEY env vars
+    fn from_env() -> Vec<Self> {
+        let mut v: Vec<Self> = std::env::vars()
+            .filter_map(|(k, v)| {
+                let key = k.strip_prefix(ENV_PREFIX)?;
+                Some(ConfigOverride {
+                    key: key.split("__").map(|k| k.to_lowercase()).collect(),
+                    value: v,
+                })
+            })
+            .collect();
+        // env order is unspecified,keep the result stable
+        v.sort_by(|a, b| a.key.cmp(&b.key));
+        v
+    }
+
+    /// set the key,to a toml value parsed from the setting unless `as_string`
+    fn apply(&self, root: &mut toml::Value, as_string: bool) -> Result<(), ApplicationError> {
+        let err = || ApplicationError::ParseConfig(format!("can not set {}", self.key.join(".")));
+        let (last, parents) = self.key.split_last().ok_or_else(err)?;
+        let mut table = root.as_table_mut().ok_or_else(err)?;
+        for k in parents {
+            table = table
+                .entry(k.clone())
+                .or_insert_with(|| toml::Value::Table(Default::default()))
+                .as_table_mut()
+                .ok_or_else(err)?;
+        }
+        // keep strings as is,so that "0660" or "1234" stay strings
+        let value = match table.get(last) {
+            _ if as_string => toml::Value::String(self.value.clone()),
+            Some(toml::Value::String(_)) => toml::Value::String(self.value.clone()),
+            _ => parse_value(&self.value),
+        };
+        table.insert(last.clone(), value);
+        Ok(())
+    }
+}
+
+/// parse a toml value such as `8080`,`true` or `["a", "b"]`,anything else is a string
+fn parse_value(raw: &str) -> toml::Value {
+    toml::from_str::<toml::Value>(&format!("v = {raw}"))
+        .ok()
+        .and_then(|mut t| t.as_table_mut()?.remove("v"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()))
+}
+
+/// merge the tables of `over` into `base`,values of `over` win
+fn merge(base: &mut toml::Value, over: toml::Value) {
+    match (base, over) {
+        (toml::Value::Table(base), toml::Value::Table(over)) => {
+            for (k, v) in over {
+                match base.get_mut(&k) {
+                    Some(b) => merge(b, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (base, over) => *base = over,
+    }
+}
+
+/// Unknown keys are rejected,so that a typo such as `[encyption]` is not silently ignored.
+/// Missing keys take their default value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
+    /// file the config was read from,used to reload it
+    #[serde(skip)]
+    source: Option<PathBuf>,
+    /// settings passed on the command line,kept to be applied again on reload
+    #[serde(skip)]
+    overrides: Vec<ConfigOverride>,
     listen: ConfigAddr,
     paths: ConfigPaths,
+    #[serde(default)]
+    log: ConfigLog,
+    #[serde(default)]
+    sync: ConfigSync,
+    #[serde(default)]
+    backup: ConfigBackup,
+    #[serde(default)]
+    admin: ConfigAdmin,
+    #[serde(default)]
+    media: ConfigMedia,
     encryption: Option<ConfigCert>,
     #[cfg(feature = "account")]
     pub account: Option<Account>,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            listen: ConfigAddr::default(),
-            paths: ConfigPaths::default(),
-            encryption: Some(ConfigCert::default()),
-            #[cfg(feature = "account")]
-            account: None,
-        }
-    }
-}
-
 impl Config {
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, ApplicationError> {
-        let mut file = File::open(path)?;
-        let mut config_string = String::new();
-        file.read_to_string(&mut config_string)?;
-        let c = toml::from_str(&config_string)?;
+        Config::load(Some(path.as_ref()), &[])
+    }
+
+    /// Build the config from,in increasing precedence,the built-in defaults,the file at
+    /// `path`,`ANKISYNCD__SECTION__KEY` env vars and `overrides` from the command line.
+    pub fn load(
+        path: Option<&Path>,
+        overrides: &[ConfigOverride],
+    ) -> Result<Self, ApplicationError> {
+        let mut value = toml::Value::try_from(Config::default())?;
+        if let Some(p) = path {
+            let text = std::fs::read_to_string(p).map_err(|e| {
+                ApplicationError::ParseConfig(format!("unable to read {}: {e}", p.display()))
+            })?;
+            // deserialize the file on its own first,so errors point at its lines
+            toml::from_str::<Config>(&text)
+                .map_err(|e| ApplicationError::ParseConfig(format!("{}: {e}", p.display())))?;
+            merge(&mut value, toml::from_str(&text)?);
+        }
+        for o in ConfigOverride::from_env().iter().chain(overrides) {
+            let before = value.clone();
+            o.apply(&mut value, false)?;
+            // keys missing from the defaults,such as unset options or the account password,
+            // have no type to go by,take the setting as a string when the parsed value does not fit
+            if value.clone().try_into::<Config>().is_err() {
+                let mut as_string = before;
+                o.apply(&mut as_string, true)?;
+                if as_string.clone().try_into::<Config>().is_ok() {
+                    value = as_string;
+                }
+            }
+        }
+        let mut c: Config = value.try_into().map_err(|e| {
+            ApplicationError::ParseConfig(format!("invalid env var or command-line setting: {e}"))
+        })?;
+        c.source = path.map(|p| p.to_owned());
+        c.overrides = overrides.to_vec();
         Ok(c)
     }
 
+    /// load the config again from the same sources
+    pub fn reread(&self) -> Result<Self, ApplicationError> {
+        Config::load(self.source.as_deref(), &self.overrides)
+    }
+
+    /// path of the file the config was read from,if any
+    pub fn source(&self) -> Option<&Path> {
+        self.source.as_deref()
+    }
+
     pub fn to_string(&self) -> Result<String, ApplicationError> {
         let s = toml::to_string(&self)?;
         Ok(s)
     }
 
+    /// the config as toml with the admin token,the s3 secret key and the account password hidden
+    pub fn to_redacted_string(&self) -> Result<String, ApplicationError> {
+        let hide = |v: &mut String| {
+            if !v.is_empty() {
+                *v = "***".to_string();
+            }
+        };
+        let mut c = self.clone();
+        hide(&mut c.admin.token);
+        hide(&mut c.media.s3.secret_key);
+        #[cfg(feature = "account")]
+        if let Some(password) = c.account.as_mut().and_then(|a| a.password.as_mut()) {
+            hide(password);
+        }
+        c.to_string()
+    }
+
     pub fn encryption_enabled(&self) -> bool {
         match &self.encryption {
             Some(e) => e.ssl_enable,
@@ -50,6 +244,23 @@ impl Config {
         format!("{}:{}", &self.listen.host, self.listen.port)
     }
 
+    /// path of the unix socket to listen on,if `host` has the form `unix:/path`
+    pub fn unix_socket(&self) -> Option<&str> {
+        self.listen.host.strip_prefix("unix:")
+    }
+
+    pub fn listen_config(&self) -> &ConfigAddr {
+        &self.listen
+    }
+
+    pub fn trusted_proxies(&self) -> &[String] {
+        &self.listen.trusted_proxies
+    }
+
+    pub fn root_dir(&self) -> &Path {
+        Path::new(&self.paths.root_dir)
+    }
+
     pub fn data_root_path(&self) -> String {
         format!("{}/collections/", self.paths.root_dir)
     }
@@ -65,12 +276,230 @@ impl Config {
     pub fn encryption_config(&self) -> Option<&ConfigCert> {
         self.encryption.as_ref()
     }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.log.format
+    }
+
+    pub fn log_level(&self) -> Result<log::LevelFilter, ApplicationError> {
+        self.log.level.parse().map_err(|_| {
+            ApplicationError::ParseConfig(format!("invalid log level {}", self.log.level))
+        })
+    }
+
+    /// describe the changed settings that only take effect after a restart
+    pub fn restart_needed(&self, new: &Config) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.listen.host != new.listen.host
+            || self.listen.port != new.listen.port
+            || self.listen.socket_mode != new.listen.socket_mode
+            || self.listen.socket_owner != new.listen.socket_owner
+        {
+            changed.push("listen address");
+        }
+        if self.paths != new.paths {
+            changed.push("root_dir");
+        }
+        if self.log.format != new.log.format {
+            changed.push("log format");
+        }
+        if self.encryption_enabled() != new.encryption_enabled() {
+            changed.push("ssl_enable");
+        }
+        if self.media.storage != new.media.storage || self.media.s3 != new.media.s3 {
+            changed.push("media storage");
+        }
+        changed
+    }
+
+    /// Look for mistakes that would only show up once the server runs,
+    /// one actionable message per problem.
+    pub fn check(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if self.log_level().is_err() {
+            problems.push(format!(
+                "log.level: unknown level {},use one of off,error,warn,info,debug,trace",
+                self.log.level
+            ));
+        }
+        if let Err(e) = crate::proxy::TrustedProxies::parse(self.trusted_proxies()) {
+            problems.push(format!("listen.trusted_proxies: {e}"));
+        }
+        if let Some(socket) = self.unix_socket() {
+            if !cfg!(unix) {
+                problems.push(format!(
+                    "listen.host: unix sockets are not supported on this platform,{socket}"
+                ));
+            }
+            // an existing socket is either stale and replaced,or passed by systemd
+            let dir = match Path::new(socket).parent() {
+                Some(d) if d.as_os_str().is_empty() => Path::new("."),
+                Some(d) => d,
+                None => Path::new("/"),
+            };
+            if !Path::new(socket).exists() {
+                if !dir.is_dir() {
+                    problems.push(format!(
+                        "listen.host: directory {} of the socket does not exist",
+                        dir.display()
+                    ));
+                } else if !writable(dir) {
+                    problems.push(format!(
+                        "listen.host: no permission to create the socket in {}",
+                        dir.display()
+                    ));
+                }
+            }
+            if self.encryption_enabled() {
+                problems.push(
+                    "listen.host: tls is not supported on a unix socket,set encryption.ssl_enable = false"
+                        .to_string(),
+                );
+            }
+        } else if self.listen.host.is_empty() {
+            problems
+                .push("listen.host is empty,use 0.0.0.0 to listen on all addresses".to_string());
+        }
+        if let Some(mode) = &self.listen.socket_mode {
+            if u32::from_str_radix(mode, 8).is_err() {
+                problems.push(format!(
+                    "listen.socket_mode: {mode} is not an octal mode like 660"
+                ));
+            }
+        }
+        #[cfg(unix)]
+        if let Some(owner) = &self.listen.socket_owner {
+            if crate::app_config::parse_socket_owner(owner).is_err() {
+                problems.push(format!(
+                    "listen.socket_owner: unknown user or group in {owner}"
+                ));
+            }
+        }
+        let root = Path::new(&self.paths.root_dir);
+        if root.exists() {
+            if !root.is_dir() {
+                problems.push(format!(
+                    "paths.root_dir: {} is not a directory",
+                    root.display()
+                ));
+            } else if !writable(root) {
+                problems.push(format!(
+                    "paths.root_dir: {} is not writable by the server user",
+                    root.display()
+                ));
+            }
+        } else {
+            // created on startup,its closest existing parent must be writable
+            let parent = root.ancestors().skip(1).find(|p| p.is_dir());
+            if !parent.is_some_and(writable) {
+                problems.push(format!(
+                    "paths.root_dir: {} does not exist and can not be created",
+                    root.display()
+                ));
+            }
+        }
+        let auth_db = PathBuf::from(self.auth_db_path());
+        if auth_db.exists() && !writable(&auth_db) {
+            problems.push(format!(
+                "{} is not writable by the server user",
+                auth_db.display()
+            ));
+        }
+        if self.media.dedup && !cfg!(unix) {
+            problems.push("media.dedup: needs hardlinks,only supported on unix".to_string());
+        }
+        if self.media.storage == StorageKind::S3 {
+            let s3 = &self.media.s3;
+            for (key, value) in [
+                ("endpoint", &s3.endpoint),
+                ("bucket", &s3.bucket),
+                ("access_key", &s3.access_key),
+                ("secret_key", &s3.secret_key),
+            ] {
+                if value.is_empty() {
+                    problems.push(format!("media.s3.{key}: required when media.storage is s3"));
+                }
+            }
+            if !s3.endpoint.is_empty()
+                && !s3.endpoint.starts_with("http://")
+                && !s3.endpoint.starts_with("https://")
+            {
+                problems.push(format!(
+                    "media.s3.endpoint: {} is not an http or https url",
+                    s3.endpoint
+                ));
+            }
+            if self.media.dedup {
+                problems.push(
+                    "media.dedup: only applies to local storage,set it to false with s3"
+                        .to_string(),
+                );
+            }
+        }
+        if self.admin_token().is_some_and(|t| t.len() < 16) {
+            problems.push("admin.token: use at least 16 characters".to_string());
+        }
+        if let Some(cert) = self.encryption.as_ref().filter(|c| c.ssl_enable) {
+            problems.extend(cert.check());
+        }
+        problems
+    }
+
+    /// run [`Config::check`],failing with every problem found
+    pub fn validate(&self) -> Result<(), ApplicationError> {
+        let problems = self.check();
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(ApplicationError::ParseConfig(format!(
+            "invalid configuration:\n  {}",
+            problems.join("\n  ")
+        )))
+    }
+
+    /// time given to active syncs to finish when shutting down
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(self.sync.shutdown_grace_period)
+    }
+
+    pub fn backup_config(&self) -> &ConfigBackup {
+        &self.backup
+    }
+
+    /// token of the admin api,`None` when only admin users may use it
+    pub fn admin_token(&self) -> Option<&str> {
+        Some(self.admin.token.as_str()).filter(|t| !t.is_empty())
+    }
+
+    pub fn media_config(&self) -> &ConfigMedia {
+        &self.media
+    }
+
+    /// where media blobs are stored once for all users,`None` when deduplication is disabled
+    pub fn media_store(&self) -> Option<PathBuf> {
+        self.media
+            .dedup
+            .then(|| self.root_dir().join(crate::dedup::STORE_DIR))
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ConfigAddr {
+    /// address to listen on,or `unix:/path/to/socket` for a unix domain socket
     pub host: String,
+    /// ignored when listening on a unix socket
     pub port: u16,
+    /// octal permissions of the unix socket,i.e. "660"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_mode: Option<String>,
+    /// owner of the unix socket as `user`,`user:group` or `:group`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socket_owner: Option<String>,
+    /// CIDRs of reverse proxies allowed to set the client address,
+    /// i.e. ["127.0.0.1/32", "::1/128"]
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
 }
 
 impl Default for ConfigAddr {
@@ -78,11 +507,15 @@ impl Default for ConfigAddr {
         ConfigAddr {
             host: "0.0.0.0".to_string(),
             port: 27701,
+            socket_mode: None,
+            socket_owner: None,
+            trusted_proxies: vec![],
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ConfigPaths {
     root_dir: String,
 }
@@ -95,16 +528,200 @@ impl Default for ConfigPaths {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+/// format of the lines written by the logger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// human readable lines,as printed by env_logger
+    #[default]
+    Text,
+    /// one json object per line
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigLog {
+    #[serde(default)]
+    pub format: LogFormat,
+    /// one of off,error,warn,info,debug,trace.RUST_LOG takes precedence when set
+    #[serde(default = "default_log_level")]
+    pub level: String,
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+impl Default for ConfigLog {
+    fn default() -> Self {
+        ConfigLog {
+            format: LogFormat::default(),
+            level: default_log_level(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigSync {
+    /// seconds to wait for active syncs on shutdown before aborting them
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period: u64,
+}
+
+fn default_shutdown_grace_period() -> u64 {
+    30
+}
+
+impl Default for ConfigSync {
+    fn default() -> Self {
+        ConfigSync {
+            shutdown_grace_period: default_shutdown_grace_period(),
+        }
+    }
+}
+
+/// snapshots of the collections kept in `collections/<user>/backups/`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigBackup {
+    /// snapshot the collection before every full upload
+    pub enabled: bool,
+    /// hours between the snapshots taken after normal syncs,0 disables them
+    pub interval_hours: u64,
+    /// number of most recent snapshots always kept
+    pub keep_last: usize,
+    /// keep the newest snapshot of each of the last N days,in local time
+    pub keep_daily: i64,
+    /// keep the newest snapshot of each of the last N weeks,starting on monday
+    pub keep_weekly: i64,
+}
+
+impl Default for ConfigBackup {
+    fn default() -> Self {
+        ConfigBackup {
+            enabled: true,
+            interval_hours: 24,
+            keep_last: 5,
+            keep_daily: 7,
+            keep_weekly: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigAdmin {
+    /// bearer token of the /admin backup endpoints,empty to only allow users with the admin role
+    pub token: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigMedia {
+    /// store identical media files of all users once,as hardlinks to `media-store/`
+    pub dedup: bool,
+    /// where media files are kept
+    pub storage: StorageKind,
+    /// used when storage is s3
+    pub s3: ConfigS3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// the media folder of each user
+    #[default]
+    Local,
+    /// an S3 compatible object store
+    S3,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigS3 {
+    /// i.e. http://127.0.0.1:9000,buckets are addressed path-style
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// prepended to `<user>/<file>` to build the object keys
+    pub prefix: String,
+}
+
+impl Default for ConfigS3 {
+    fn default() -> Self {
+        ConfigS3 {
+            endpoint: String::new(),
+            bucket: String::new(),
+            region: "us-east-1".to_string(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            prefix: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct ConfigCert {
     ssl_enable: bool,
     pub cert_file: String,
     pub key_file: String,
 }
 
+impl ConfigCert {
+    fn check(&self) -> Vec<String> {
+        if !cfg!(feature = "tls") {
+            return vec![
+                "encryption.ssl_enable: this binary was built without tls support,set it to false or rebuild with --features tls"
+                    .to_string(),
+            ];
+        }
+        let mut problems = vec![];
+        for (key, file) in [("cert_file", &self.cert_file), ("key_file", &self.key_file)] {
+            if file.is_empty() {
+                problems.push(format!(
+                    "encryption.{key} must be set when ssl_enable = true"
+                ));
+            } else if let Err(e) = std::fs::File::open(file) {
+                problems.push(format!("encryption.{key}: unable to read {file}: {e}"));
+            }
+        }
+        #[cfg(feature = "tls")]
+        if problems.is_empty() {
+            match crate::app_config::check_key_pair(self) {
+                Ok(()) => {}
+                Err(ApplicationError::ParseConfig(e)) => problems.push(format!("encryption: {e}")),
+                Err(e) => problems.push(format!("encryption: unable to load the key pair: {e}")),
+            }
+        }
+        problems
+    }
+}
+
+/// whether the server user may write to `path`
+#[cfg(unix)]
+fn writable(path: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(p) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: p is a valid nul terminated string
+    unsafe { libc::access(p.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+fn writable(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| !m.permissions().readonly())
+}
+
 /// account in config file
 #[cfg(feature = "account")]
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
 pub struct Account {
     username: Option<String>,
     password: Option<String>,
@@ -126,3 +743,67 @@ impl Account {
             .map(|e| e.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(settings: &[&str]) -> Result<Config, ApplicationError> {
+        let overrides: Vec<_> = settings
+            .iter()
+            .map(|s| ConfigOverride::parse(s).unwrap())
+            .collect();
+        Config::load(None, &overrides)
+    }
+
+    #[test]
+    fn unset_options_are_set_as_strings() {
+        let c = load(&["listen.socket_mode=660", "listen.socket_owner=1000"]).unwrap();
+        assert_eq!(c.listen.socket_mode.as_deref(), Some("660"));
+        assert_eq!(c.listen.socket_owner.as_deref(), Some("1000"));
+    }
+
+    #[test]
+    fn settings_keep_the_type_of_their_key() {
+        let c = load(&[
+            "listen.port=8080",
+            "listen.trusted_proxies=[\"10.0.0.0/8\"]",
+            "admin.token=1234567890123456",
+            "media.dedup=true",
+        ])
+        .unwrap();
+        assert_eq!(c.listen.port, 8080);
+        assert_eq!(c.listen.trusted_proxies, ["10.0.0.0/8"]);
+        assert_eq!(c.admin.token, "1234567890123456");
+        assert!(c.media.dedup);
+        assert!(load(&["listen.port=http"]).is_err());
+        assert!(load(&["listen.no_such_key=1"]).is_err());
+    }
+
+    #[cfg(feature = "account")]
+    #[test]
+    fn numeric_account_settings_are_strings() {
+        let c = load(&["account.username=1234", "account.password=5678"]).unwrap();
+        let account = c.account.unwrap();
+        assert_eq!(account.username().as_deref(), Some("1234"));
+        assert_eq!(account.password().as_deref(), Some("5678"));
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let c = load(&[
+            "admin.token=abcdefghijklmnopq",
+            "media.s3.secret_key=wJalrXUtnFEMI",
+            "media.s3.access_key=AKIDEXAMPLE",
+        ])
+        .unwrap();
+        let shown = c.to_redacted_string().unwrap();
+        assert!(!shown.contains("abcdefghijklmnopq"));
+        assert!(!shown.contains("wJalrXUtnFEMI"));
+        assert!(shown.contains("AKIDEXAMPLE"));
+        assert_eq!(shown.matches("\"***\"").count(), 2);
+        // unset secrets stay empty
+        let shown = Config::default().to_redacted_string().unwrap();
+        assert!(!shown.contains("***"));
+    }
+}
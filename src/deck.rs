@@ -0,0 +1,327 @@
+// decks maintained by an admin and merged into the collections of a group of users,
+// i.e. ankisyncd deck publish --group biology --name "Biology 101" biology.apkg
+use crate::error::ApplicationError;
+use crate::group::{group_exists, members};
+use crate::import::{import_package, ImportReport};
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CREATE_DECK_TABLES: &str = "CREATE TABLE IF NOT EXISTS shared_decks (
+    name TEXT PRIMARY KEY,
+    groupname TEXT NOT NULL,
+    -- latest published revision,starting at 1
+    revision INTEGER NOT NULL,
+    published INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS deck_subscribers (
+    deck TEXT NOT NULL,
+    username TEXT NOT NULL,
+    -- revision merged into the collection of the user
+    revision INTEGER NOT NULL,
+    applied INTEGER NOT NULL,
+    PRIMARY KEY (deck, username)
+);";
+/// revisions are kept as `shared-decks/<name>/<revision>.apkg` in the root dir
+const SHARED_DECKS_DIR: &str = "shared-decks";
+
+pub(crate) fn create_deck_tables(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch(CREATE_DECK_TABLES)
+}
+
+fn open<P: AsRef<Path>>(dbpath: P) -> Result<Connection, ApplicationError> {
+    let conn = Connection::open(dbpath)?;
+    create_deck_tables(&conn)?;
+    Ok(conn)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone)]
+pub struct SharedDeck {
+    pub name: String,
+    pub group: String,
+    pub revision: i64,
+    /// local time of the latest revision
+    pub published: String,
+}
+
+const SELECT_DECKS: &str =
+    "SELECT name,groupname,revision,datetime(published,'unixepoch','localtime') FROM shared_decks";
+
+fn deck_from_row(r: &Row) -> rusqlite::Result<SharedDeck> {
+    Ok(SharedDeck {
+        name: r.get(0)?,
+        group: r.get(1)?,
+        revision: r.get(2)?,
+        published: r.get(3)?,
+    })
+}
+
+/// the name becomes a folder,keep it to a single path component
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && !name
+            .chars()
+            .any(|c| c == '/' || c == '\\' || c.is_control())
+}
+
+fn package_path(root_dir: &Path, name: &str, revision: i64) -> PathBuf {
+    root_dir
+        .join(SHARED_DECKS_DIR)
+        .join(name)
+        .join(format!("{revision}.apkg"))
+}
+
+pub fn list_decks<P: AsRef<Path>>(dbpath: P) -> Result<Vec<SharedDeck>, ApplicationError> {
+    let conn = open(dbpath)?;
+    let mut stmt = conn.prepare(&format!("{SELECT_DECKS} ORDER BY name"))?;
+    let rows = stmt.query_map([], deck_from_row)?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+fn find_deck<P: AsRef<Path>>(
+    name: &str,
+    dbpath: P,
+) -> Result<Option<SharedDeck>, ApplicationError> {
+    Ok(open(dbpath)?
+        .query_row(
+            &format!("{SELECT_DECKS} WHERE name=?"),
+            [name],
+            deck_from_row,
+        )
+        .optional()?)
+}
+
+/// names of the shared decks sent to `group`
+pub fn decks_of_group<P: AsRef<Path>>(
+    group: &str,
+    dbpath: P,
+) -> Result<Vec<String>, ApplicationError> {
+    let conn = open(dbpath)?;
+    let mut stmt = conn.prepare("SELECT name FROM shared_decks WHERE groupname=? ORDER BY name")?;
+    let rows = stmt.query_map([group], |r| r.get(0))?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// (members having the latest revision,members of the group)
+pub fn deck_status<P: AsRef<Path>>(
+    deck: &SharedDeck,
+    dbpath: P,
+) -> Result<(usize, usize), ApplicationError> {
+    let members = members(&deck.group, &dbpath)?;
+    let conn = open(dbpath)?;
+    let mut current = 0;
+    for user in &members {
+        if applied_revision(&conn, &deck.name, user)? >= deck.revision {
+            current += 1;
+        }
+    }
+    Ok((current, members.len()))
+}
+
+/// Store `package` as the next revision of the shared deck `name`.
+///
+/// A new shared deck needs the group it is sent to,an existing one keeps its group unless given.
+/// Every revision is a complete apkg of the deck,members only ever receive the latest.
+pub fn publish<P: AsRef<Path>>(
+    root_dir: &Path,
+    dbpath: P,
+    name: &str,
+    group: Option<&str>,
+    package: &Path,
+) -> Result<SharedDeck, ApplicationError> {
+    if !valid_name(name) {
+        return Err(ApplicationError::ValueNotFound(format!(
+            "{name:?} can not name a shared deck,it must not be empty,start with a dot or hold a slash"
+        )));
+    }
+    if package.extension().and_then(|e| e.to_str()) != Some("apkg") || !package.is_file() {
+        return Err(ApplicationError::NotFound(format!(
+            "no apkg {}",
+            package.display()
+        )));
+    }
+    let mut conn = open(&dbpath)?;
+    // concurrent publishes of the deck wait here,so each gets its own revision
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    let existing = tx
+        .query_row(
+            &format!("{SELECT_DECKS} WHERE name=?"),
+            [name],
+            deck_from_row,
+        )
+        .optional()?;
+    let group = match (group, &existing) {
+        (Some(g), _) => g.to_string(),
+        (None, Some(d)) => d.group.clone(),
+        (None, None) => {
+            return Err(ApplicationError::ValueNotFound(format!(
+                "{name} is a new shared deck,give the group to send it to"
+            )))
+        }
+    };
+    if !group_exists(&group, &dbpath)? {
+        return Err(ApplicationError::NotFound(format!("no group {group}")));
+    }
+    let revision = existing.map_or(1, |d| d.revision + 1);
+    let path = package_path(root_dir, name, revision);
+    let dir = path.parent().expect("package in a folder");
+    fs::create_dir_all(dir)?;
+    // the revision only shows up once complete
+    let tmp = dir.join(format!(".{revision}.apkg"));
+    fs::copy(package, &tmp)?;
+    fs::rename(&tmp, &path)?;
+    let stored = tx
+        .execute(
+            "INSERT OR REPLACE INTO shared_decks VALUES (?, ?, ?, ?)",
+            params![name, group, revision, now()],
+        )
+        .and_then(|_| tx.commit());
+    if let Err(e) = stored {
+        let _ = fs::remove_file(&path);
+        return Err(e.into());
+    }
+    find_deck(name, dbpath)?.ok_or_else(|| ApplicationError::NotFound(format!("no deck {name}")))
+}
+
+/// Stop sending a shared deck and delete its revisions,
+/// the notes already merged stay in the collections.
+pub fn remove_deck<P: AsRef<Path>>(
+    root_dir: &Path,
+    dbpath: P,
+    name: &str,
+) -> Result<(), ApplicationError> {
+    if find_deck(name, &dbpath)?.is_none() {
+        return Err(ApplicationError::NotFound(format!("no shared deck {name}")));
+    }
+    let mut conn = open(dbpath)?;
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM deck_subscribers WHERE deck=?", [name])?;
+    tx.execute("DELETE FROM shared_decks WHERE name=?", [name])?;
+    tx.commit()?;
+    let dir = root_dir.join(SHARED_DECKS_DIR).join(name);
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    Ok(())
+}
+
+/// revision of `deck` merged into the collection of `user`,0 if none
+fn applied_revision(conn: &Connection, deck: &str, user: &str) -> Result<i64, ApplicationError> {
+    let revision = conn
+        .query_row(
+            "SELECT revision FROM deck_subscribers WHERE deck=? AND username=?",
+            [deck, user],
+            |r| r.get(0),
+        )
+        .optional()?;
+    Ok(revision.unwrap_or(0))
+}
+
+/// a revision merged into the collection of a member
+#[derive(Debug)]
+pub struct DeckUpdate {
+    pub deck: String,
+    pub user: String,
+    pub revision: i64,
+    pub result: Result<ImportReport, ApplicationError>,
+}
+
+/// Merge the latest revision of the shared decks into the collections of the members
+/// of their group who do not have it yet,only for `deck` and `user` when given.
+/// The server must be stopped.
+///
+/// The revision is imported with anki's apkg import,which adds new notes and updates
+/// the notes edited since,matched by their guid.Cards already in a collection keep
+/// the scheduling of their user.A failed member is retried on the next run.
+/// `store` is the media store when media is deduplicated.
+pub fn apply_pending<P: AsRef<Path>>(
+    root_dir: &Path,
+    dbpath: P,
+    data_root: &Path,
+    store: Option<&Path>,
+    deck: Option<&str>,
+    user: Option<&str>,
+) -> Result<Vec<DeckUpdate>, ApplicationError> {
+    let decks = match deck {
+        Some(name) => vec![find_deck(name, &dbpath)?
+            .ok_or_else(|| ApplicationError::NotFound(format!("no shared deck {name}")))?],
+        None => list_decks(&dbpath)?,
+    };
+    let conn = open(&dbpath)?;
+    let mut updates = vec![];
+    for deck in decks {
+        let package = package_path(root_dir, &deck.name, deck.revision);
+        for member in members(&deck.group, &dbpath)? {
+            if user.is_some_and(|u| u != member)
+                || applied_revision(&conn, &deck.name, &member)? >= deck.revision
+            {
+                continue;
+            }
+            let result = import_package(&data_root.join(&member), &package, store);
+            if result.is_ok() {
+                conn.execute(
+                    "INSERT OR REPLACE INTO deck_subscribers VALUES (?, ?, ?, ?)",
+                    params![deck.name, member, deck.revision, now()],
+                )?;
+            }
+            updates.push(DeckUpdate {
+                deck: deck.name.clone(),
+                user: member,
+                revision: deck.revision,
+                result,
+            });
+        }
+    }
+    Ok(updates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group::create_group;
+
+    #[test]
+    fn concurrent_publishes_get_their_own_revision() {
+        let root =
+            std::env::temp_dir().join(format!("ankisyncd-deck-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&root).unwrap();
+        let dbpath = root.join("auth.db");
+        create_group("biology", &dbpath).unwrap();
+        let threads: Vec<_> = (0..2)
+            .map(|t| {
+                let (root, dbpath) = (root.clone(), dbpath.clone());
+                std::thread::spawn(move || {
+                    let package = root.join(format!("{t}.apkg"));
+                    fs::write(&package, format!("{t}")).unwrap();
+                    (0..5)
+                        .map(|_| {
+                            publish(&root, &dbpath, "Biology 101", Some("biology"), &package)
+                                .unwrap()
+                                .revision
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut revisions: Vec<_> = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect();
+        revisions.sort();
+        assert_eq!(revisions, (1..=10).collect::<Vec<_>>());
+        let packages = fs::read_dir(root.join(SHARED_DECKS_DIR).join("Biology 101"))
+            .unwrap()
+            .count();
+        assert_eq!(packages, 10);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
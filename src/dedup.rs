@@ -0,0 +1,197 @@
+// content-addressed media: every media file of every user is a hardlink to a blob
+// stored once under {root_dir}/media-store/<first two hex digits>/<sha1>.
+// the link count of a blob is its reference count,a blob only linked from the
+// store is unused and removed by `collect_garbage`.
+use crate::backup::user_of;
+use crate::error::ApplicationError;
+use crate::media::{check_media_names, media_folder, sha1_file};
+use actix_web::web;
+use anki::sync::http_server::SimpleServer;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+pub const STORE_DIR: &str = "media-store";
+
+fn blob_path(store: &Path, sha1: &str) -> PathBuf {
+    store.join(&sha1[..2]).join(sha1)
+}
+
+#[cfg(unix)]
+fn link_count(meta: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink()
+}
+
+/// unknown,treated as shared so that nothing is linked or collected
+#[cfg(not(unix))]
+fn link_count(_meta: &fs::Metadata) -> u64 {
+    u64::MAX
+}
+
+/// a temporary name next to `path`,replaced atomically by a rename
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".dedup-tmp");
+    path.with_file_name(name)
+}
+
+/// Make `path` a link to its blob,adding the blob if the store does not have it.
+/// Returns the bytes saved,0 when the content was new or the file already linked.
+fn link_file(store: &Path, path: &Path) -> Result<u64, ApplicationError> {
+    let meta = fs::metadata(path)?;
+    if link_count(&meta) > 1 {
+        return Ok(0);
+    }
+    let blob = blob_path(store, &sha1_file(path)?);
+    if !blob.exists() {
+        fs::create_dir_all(blob.parent().expect("blob parent"))?;
+        fs::hard_link(path, &blob)?;
+        return Ok(0);
+    }
+    let tmp = temp_path(path);
+    let _ = fs::remove_file(&tmp);
+    fs::hard_link(&blob, &tmp)?;
+    fs::rename(&tmp, path)?;
+    Ok(meta.len())
+}
+
+/// what a deduplication pass did
+#[derive(Debug, Default)]
+pub struct DedupSummary {
+    pub files: usize,
+    pub saved: u64,
+}
+
+/// Link the given media files of the user to the store,ignoring names not in the folder.
+pub fn link_files<'a>(
+    store: &Path,
+    user_folder: &Path,
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<DedupSummary, ApplicationError> {
+    let folder = media_folder(user_folder);
+    let mut summary = DedupSummary::default();
+    for name in names {
+        let path = folder.join(name);
+        if !path.is_file() {
+            continue;
+        }
+        summary.saved += link_file(store, &path)?;
+        summary.files += 1;
+    }
+    Ok(summary)
+}
+
+/// Link every media file of the user to the store,the server must be stopped.
+pub fn dedup_user(store: &Path, user_folder: &Path) -> Result<DedupSummary, ApplicationError> {
+    let folder = media_folder(user_folder);
+    if !folder.is_dir() {
+        return Ok(DedupSummary::default());
+    }
+    let mut names = vec![];
+    for entry in fs::read_dir(&folder)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    link_files(store, user_folder, names.iter().map(String::as_str))
+}
+
+/// Give the user a private copy of the named files that are linked to the store.
+///
+/// anki writes uploaded files in place,which would change the blob of every user linking it.
+pub fn unlink_files<'a>(
+    user_folder: &Path,
+    names: impl IntoIterator<Item = &'a str>,
+) -> Result<(), ApplicationError> {
+    let folder = media_folder(user_folder);
+    for name in names {
+        let path = folder.join(name);
+        match fs::metadata(&path) {
+            Ok(meta) if link_count(&meta) > 1 => {
+                let tmp = temp_path(&path);
+                fs::copy(&path, &tmp)?;
+                fs::rename(&tmp, &path)?;
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// names of the files added or deleted by a media upload,read from its `_meta` entry
+pub fn uploaded_names(data: &[u8]) -> Result<Vec<String>, ApplicationError> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(data))?;
+    let mut meta = String::new();
+    zip.by_name("_meta")?.read_to_string(&mut meta)?;
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&meta)?;
+    Ok(entries
+        .iter()
+        .filter_map(|e| e.get(0).and_then(|n| n.as_str()).map(|n| n.to_string()))
+        .collect())
+}
+
+/// Remove the blobs no user links anymore,returns their number and size.
+pub fn collect_garbage(store: &Path) -> Result<(usize, u64), ApplicationError> {
+    let (mut removed, mut size) = (0, 0);
+    if !store.is_dir() {
+        return Ok((removed, size));
+    }
+    for dir in fs::read_dir(store)? {
+        let dir = dir?;
+        if !dir.file_type()?.is_dir() {
+            continue;
+        }
+        for blob in fs::read_dir(dir.path())? {
+            let blob = blob?;
+            let meta = blob.metadata()?;
+            if meta.is_file() && link_count(&meta) == 1 {
+                fs::remove_file(blob.path())?;
+                removed += 1;
+                size += meta.len();
+            }
+        }
+    }
+    Ok((removed, size))
+}
+
+/// Prepare the folder of the user owning `sync_key` for a media upload,
+/// returning it with the names of the uploaded files.
+///
+/// Runs whether deduplication is enabled or not,as linked files may be left from when it was.
+pub async fn before_media_upload(
+    server: &SimpleServer,
+    sync_key: &str,
+    data: &[u8],
+) -> Result<Option<(PathBuf, Vec<String>)>, ApplicationError> {
+    let Some((_, folder)) = user_of(server, sync_key) else {
+        return Ok(None);
+    };
+    // an invalid upload is left to anki to reject
+    let Ok(names) = uploaded_names(data) else {
+        return Ok(None);
+    };
+    check_media_names(&names)?;
+    let result = {
+        let (folder, names) = (folder.clone(), names.clone());
+        web::block(move || unlink_files(&folder, names.iter().map(String::as_str))).await
+    };
+    result.map_err(|e| ApplicationError::InternalServerError(e.to_string()))??;
+    Ok(Some((folder, names)))
+}
+
+/// Link the files of a finished media upload to the store.
+///
+/// The files stay private copies if it fails,the error is logged.
+pub async fn after_media_upload(store: PathBuf, folder: PathBuf, names: Vec<String>) {
+    let result =
+        web::block(move || link_files(&store, &folder, names.iter().map(String::as_str))).await;
+    match result {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => log::error!("unable to deduplicate uploaded media: {e}"),
+        Err(e) => log::error!("unable to deduplicate uploaded media: {e}"),
+    }
+}
@@ -45,6 +45,15 @@ pub enum ApplicationError {
     SimpleServer(String),
     #[error("request url not found: {0}")]
     HttpError(#[from] anki::sync::error::HttpError),
+    /// 404
+    #[error("Not found: {0}")]
+    NotFound(String),
+    /// 409,i.e. the user is syncing
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    /// 400,i.e. a media file name that is not a plain file name
+    #[error("Bad request: {0}")]
+    BadRequest(String),
 }
 
 /// Actix Web uses `ResponseError` for conversion of errors to a response
@@ -61,6 +70,9 @@ impl ResponseError for ApplicationError {
                 log::error!("{}", e.to_string());
                 HttpResponse::Forbidden().finish()
             }
+            ApplicationError::NotFound(e) => HttpResponse::NotFound().body(e.to_string()),
+            ApplicationError::Conflict(e) => HttpResponse::Conflict().body(e.to_string()),
+            ApplicationError::BadRequest(e) => HttpResponse::BadRequest().body(e.to_string()),
             e => {
                 log::error!("{}", e.to_string());
                 HttpResponse::InternalServerError().finish()
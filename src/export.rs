@@ -0,0 +1,219 @@
+// export a user's collection as a package anki can import,
+// i.e. ankisyncd export --user alice --format apkg --out alice.apkg,
+// or GET /export?format=colpkg with the user's own credentials.
+use crate::audit::{AuditEntry, AuditLog};
+use crate::db::fetch_users;
+use crate::error::ApplicationError;
+use crate::media::media_folder;
+use crate::metrics::METRICS;
+use crate::storage::MediaStorage;
+use crate::user::compute_hash;
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use anki::collection::CollectionBuilder;
+use anki::search::SearchNode;
+use async_std::io::ReadExt;
+use base64::Engine;
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+const COLLECTION_FILE: &str = "collection.anki2";
+
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// the whole collection with its media,replacing the collection when imported
+    #[default]
+    Colpkg,
+    /// notes and cards with their scheduling and media,merged into the collection when imported
+    Apkg,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Colpkg => "colpkg",
+            ExportFormat::Apkg => "apkg",
+        }
+    }
+}
+
+/// Export the collection of the user in `user_folder` to `out`,only the notes of `deck`
+/// and its subdecks if given,which needs the apkg format.
+///
+/// The export runs on a copy of the collection,which is left untouched
+/// and may be synced meanwhile.The media files are read from the media folder,
+/// so a remote media storage is refused.
+pub fn export_collection(
+    user_folder: &Path,
+    storage: &dyn MediaStorage,
+    format: ExportFormat,
+    deck: Option<&str>,
+    out: &Path,
+) -> Result<(), ApplicationError> {
+    if storage.is_remote() {
+        return Err(ApplicationError::ParseConfig(
+            "export only works with local media storage".to_string(),
+        ));
+    }
+    if deck.is_some() && format == ExportFormat::Colpkg {
+        return Err(ApplicationError::ValueNotFound(
+            "a colpkg always holds the whole collection,use apkg to export a deck".to_string(),
+        ));
+    }
+    let path = user_folder.join(COLLECTION_FILE);
+    if !path.exists() {
+        return Err(ApplicationError::NotFound(format!(
+            "no collection in {}",
+            user_folder.display()
+        )));
+    }
+    let work = user_folder.join(format!(".export-{:016x}", rand::random::<u64>()));
+    fs::create_dir_all(&work)?;
+    let result = (|| -> Result<(), ApplicationError> {
+        let copy = work.join(COLLECTION_FILE);
+        Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)?.backup(
+            DatabaseName::Main,
+            &copy,
+            None,
+        )?;
+        // the media database of the server has another format,give anki one of its own
+        let mut col = CollectionBuilder::new(&copy)
+            .set_media_paths(media_folder(user_folder), work.join("media.db"))
+            .build()?;
+        match format {
+            ExportFormat::Colpkg => col.export_colpkg(out, true, false)?,
+            ExportFormat::Apkg => {
+                let search = match deck {
+                    Some(name) => SearchNode::from_deck_name(name),
+                    None => SearchNode::WholeCollection,
+                };
+                col.export_apkg(out, search, true, true, false, None)?;
+                col.close(None)?;
+            }
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_dir_all(&work);
+    if result.is_err() {
+        let _ = fs::remove_file(out);
+    }
+    result
+}
+
+/// name of the user whose `Authorization: Basic` credentials match auth.db
+pub(crate) fn authenticate(
+    req: &HttpRequest,
+    auth_db: &str,
+) -> Result<Option<String>, ApplicationError> {
+    let credentials = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|b| base64::engine::general_purpose::STANDARD.decode(b).ok())
+        .and_then(|b| String::from_utf8(b).ok());
+    let Some((username, password)) = credentials.as_deref().and_then(|c| c.split_once(':')) else {
+        return Ok(None);
+    };
+    let users = fetch_users(auth_db)?.unwrap_or_default();
+    let valid = users
+        .iter()
+        .any(|(name, hash)| name == username && compute_hash(username, password, hash) == *hash);
+    if !valid {
+        METRICS.inc_auth_failures();
+        return Ok(None);
+    }
+    Ok(Some(username.to_string()))
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    deck: Option<String>,
+}
+
+/// Download a package of the collection of the authenticated user.
+#[get("/export")]
+async fn export_download(
+    req: HttpRequest,
+    query: web::Query<ExportQuery>,
+    auth_db: web::Data<String>,
+    base_folder: web::Data<PathBuf>,
+    audit: web::Data<AuditLog>,
+    storage: web::Data<Arc<dyn MediaStorage>>,
+) -> actix_web::Result<HttpResponse> {
+    let Some(username) = authenticate(&req, &auth_db)? else {
+        return Ok(HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"ankisyncd\""))
+            .finish());
+    };
+    let ExportQuery { format, deck } = query.into_inner();
+    if deck.is_some() && format == ExportFormat::Colpkg {
+        return Ok(HttpResponse::BadRequest().body("use format=apkg to export a deck"));
+    }
+    if storage.is_remote() {
+        return Ok(
+            HttpResponse::NotImplemented().body("export only works with local media storage")
+        );
+    }
+    let folder = base_folder.join(&username);
+    let out = folder.join(format!(
+        ".export-{:016x}.{}",
+        rand::random::<u64>(),
+        format.extension()
+    ));
+    {
+        let (storage, out) = (storage.get_ref().clone(), out.clone());
+        web::block(move || {
+            export_collection(&folder, storage.as_ref(), format, deck.as_deref(), &out)
+        })
+        .await??;
+    }
+    // removed once the response is sent,or when the client goes away
+    let exported = ExportedFile(out);
+    let file = async_std::fs::File::open(&exported.0).await?;
+    let len = file.metadata().await?.len();
+    audit
+        .record(vec![
+            AuditEntry::event(&username, "export", "ok").detail(format.extension())
+        ])
+        .await;
+    let filename = format!(
+        "{username}-{}.{}",
+        chrono::Local::now().format("%Y%m%d"),
+        format.extension()
+    );
+    let body = futures_util::stream::unfold((file, exported), |(mut file, exported)| async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(web::Bytes::from(buf)), (file, exported)))
+            }
+            Err(e) => Some((Err(e), (file, exported))),
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{filename}\""),
+        ))
+        .no_chunking(len)
+        .streaming(body))
+}
+
+/// an exported package,deleted when dropped
+struct ExportedFile(PathBuf);
+
+impl Drop for ExportedFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
@@ -0,0 +1,173 @@
+// groups of users kept in auth.db,i.e. the students of a class receiving a shared deck
+// with their teacher as admin of the group
+use crate::error::ApplicationError;
+use crate::user::{user_exists, Role};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+const CREATE_GROUP_TABLES: &str = "CREATE TABLE IF NOT EXISTS groups (name TEXT PRIMARY KEY);
+CREATE TABLE IF NOT EXISTS user_groups (
+    username TEXT NOT NULL,
+    groupname TEXT NOT NULL,
+    -- user or admin,an admin of a group may publish its shared decks
+    role TEXT NOT NULL DEFAULT 'user',
+    PRIMARY KEY (username, groupname)
+);";
+
+pub(crate) fn create_group_tables(conn: &Connection) -> Result<(), rusqlite::Error> {
+    conn.execute_batch(CREATE_GROUP_TABLES)?;
+    // tables created before roles existed
+    let has_role = conn
+        .prepare("SELECT 1 FROM pragma_table_info('user_groups') WHERE name='role'")?
+        .exists([])?;
+    if !has_role {
+        conn.execute(
+            "ALTER TABLE user_groups ADD COLUMN role TEXT NOT NULL DEFAULT 'user'",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn open<P: AsRef<Path>>(dbpath: P) -> Result<Connection, ApplicationError> {
+    let conn = Connection::open(dbpath)?;
+    create_group_tables(&conn)?;
+    Ok(conn)
+}
+
+pub fn group_exists<P: AsRef<Path>>(name: &str, dbpath: P) -> Result<bool, ApplicationError> {
+    let found = open(dbpath)?
+        .query_row("SELECT 1 FROM groups WHERE name=?", [name], |_| Ok(()))
+        .optional()?;
+    Ok(found.is_some())
+}
+
+fn ensure_group<P: AsRef<Path>>(name: &str, dbpath: P) -> Result<(), ApplicationError> {
+    if !group_exists(name, dbpath)? {
+        return Err(ApplicationError::NotFound(format!("no group {name}")));
+    }
+    Ok(())
+}
+
+pub fn create_group<P: AsRef<Path>>(name: &str, dbpath: P) -> Result<(), ApplicationError> {
+    if name.is_empty() {
+        return Err(ApplicationError::ValueNotFound(
+            "a group needs a name".to_string(),
+        ));
+    }
+    if group_exists(name, &dbpath)? {
+        return Err(ApplicationError::Conflict(format!(
+            "group {name} already exists"
+        )));
+    }
+    open(dbpath)?.execute("INSERT INTO groups VALUES (?)", [name])?;
+    Ok(())
+}
+
+/// delete a group and its memberships,the users themselves are kept
+pub fn delete_group<P: AsRef<Path>>(name: &str, dbpath: P) -> Result<(), ApplicationError> {
+    ensure_group(name, &dbpath)?;
+    let mut conn = open(dbpath)?;
+    let tx = conn.transaction()?;
+    tx.execute("DELETE FROM user_groups WHERE groupname=?", [name])?;
+    tx.execute("DELETE FROM groups WHERE name=?", [name])?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// add users to a group with `role`,changing the role of those already in it
+pub fn add_members<P: AsRef<Path>>(
+    name: &str,
+    users: &[String],
+    role: Role,
+    dbpath: P,
+) -> Result<(), ApplicationError> {
+    ensure_group(name, &dbpath)?;
+    for user in users {
+        if !user_exists(user, &dbpath)? {
+            return Err(ApplicationError::NotFound(format!("no user {user}")));
+        }
+    }
+    let mut conn = open(dbpath)?;
+    let tx = conn.transaction()?;
+    for user in users {
+        tx.execute(
+            "INSERT OR REPLACE INTO user_groups (username,groupname,role) VALUES (?, ?, ?)",
+            params![user, name, role.as_str()],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn remove_members<P: AsRef<Path>>(
+    name: &str,
+    users: &[String],
+    dbpath: P,
+) -> Result<(), ApplicationError> {
+    ensure_group(name, &dbpath)?;
+    let conn = open(dbpath)?;
+    for user in users {
+        conn.execute(
+            "DELETE FROM user_groups WHERE username=? AND groupname=?",
+            params![user, name],
+        )?;
+    }
+    Ok(())
+}
+
+/// members of a group,sorted by name
+pub fn members<P: AsRef<Path>>(name: &str, dbpath: P) -> Result<Vec<String>, ApplicationError> {
+    ensure_group(name, &dbpath)?;
+    let conn = open(dbpath)?;
+    let mut stmt =
+        conn.prepare("SELECT username FROM user_groups WHERE groupname=? ORDER BY username")?;
+    let rows = stmt.query_map([name], |r| r.get(0))?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// members of a group with their role in it,sorted by name
+pub fn member_roles<P: AsRef<Path>>(
+    name: &str,
+    dbpath: P,
+) -> Result<Vec<(String, Role)>, ApplicationError> {
+    ensure_group(name, &dbpath)?;
+    let conn = open(dbpath)?;
+    let mut stmt =
+        conn.prepare("SELECT username,role FROM user_groups WHERE groupname=? ORDER BY username")?;
+    let rows = stmt.query_map([name], |r| {
+        Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+    })?;
+    let mut members = vec![];
+    for row in rows {
+        let (user, role) = row?;
+        members.push((user, role.parse()?));
+    }
+    Ok(members)
+}
+
+/// whether `user` is an admin of `group`
+pub fn is_group_admin<P: AsRef<Path>>(
+    user: &str,
+    group: &str,
+    dbpath: P,
+) -> Result<bool, ApplicationError> {
+    let found = open(dbpath)?
+        .query_row(
+            "SELECT 1 FROM user_groups WHERE username=? AND groupname=? AND role='admin'",
+            [user, group],
+            |_| Ok(()),
+        )
+        .optional()?;
+    Ok(found.is_some())
+}
+
+/// every group with its number of members
+pub fn list_groups<P: AsRef<Path>>(dbpath: P) -> Result<Vec<(String, i64)>, ApplicationError> {
+    let conn = open(dbpath)?;
+    let mut stmt = conn.prepare(
+        "SELECT name,(SELECT count() FROM user_groups WHERE groupname=name) FROM groups ORDER BY name",
+    )?;
+    let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
@@ -0,0 +1,211 @@
+// import a package into a user's collection while the server is stopped,
+// i.e. ankisyncd import --user alice deck.apkg
+use crate::backup::{take_snapshot, SnapshotReason};
+use crate::dedup::{dedup_user, unlink_files};
+use crate::error::ApplicationError;
+use crate::media::{media_folder, sha1_file, MediaDb};
+use anki::collection::CollectionBuilder;
+use anki::import_export::package::import_colpkg;
+use anki::sync::http_server::media_manager::ServerMediaManager;
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+const COLLECTION_FILE: &str = "collection.anki2";
+/// tables whose rows carry a usn,-1 marking changes not synced yet
+const USN_TABLES: [&str; 8] = [
+    "notes",
+    "cards",
+    "revlog",
+    "graves",
+    "decks",
+    "deck_config",
+    "notetypes",
+    "tags",
+];
+
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub new_notes: usize,
+    pub updated_notes: usize,
+    /// notes already in the collection,left as they are
+    pub duplicate_notes: usize,
+    /// media files added or changed,recorded in the media database
+    pub media_files: Vec<String>,
+    /// whether the collection was replaced,clients then need a full sync
+    pub replaced: bool,
+    /// id of the snapshot taken before importing
+    pub snapshot: Option<String>,
+}
+
+/// size and modification time of the files in the media folder
+fn media_state(folder: &Path) -> Result<HashMap<String, (u64, SystemTime)>, ApplicationError> {
+    let mut state = HashMap::new();
+    if !folder.is_dir() {
+        return Ok(state);
+    }
+    for entry in fs::read_dir(folder)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_file() {
+            state.insert(
+                entry.file_name().to_string_lossy().to_string(),
+                (meta.len(), meta.modified()?),
+            );
+        }
+    }
+    Ok(state)
+}
+
+/// Record the media files written since `before` with new usns,so clients download them.
+fn record_media(
+    user_folder: &Path,
+    before: &HashMap<String, (u64, SystemTime)>,
+) -> Result<Vec<String>, ApplicationError> {
+    let folder = media_folder(user_folder);
+    let mut changed: Vec<_> = media_state(&folder)?
+        .into_iter()
+        .filter(|(name, state)| before.get(name) != Some(state))
+        .map(|(name, _)| name)
+        .collect();
+    changed.sort();
+    let mut db = MediaDb::open(user_folder)?;
+    let entries = db.entries()?;
+    db.transaction(|db| {
+        let mut recorded = vec![];
+        for name in changed {
+            let path = folder.join(&name);
+            let csum = sha1_file(&path)?;
+            if entries.get(&name) == Some(&Some(csum.clone())) {
+                continue;
+            }
+            db.record_change(&name, Some(&csum), fs::metadata(&path)?.len())?;
+            recorded.push(name);
+        }
+        Ok(recorded)
+    })
+}
+
+/// Merge the notes,cards and media of an apkg into the collection.
+///
+/// The collection is opened as the server's,so anki gives the imported objects
+/// the current usn instead of marking them as pending client changes,
+/// and clients pull them with a normal sync.Existing notetypes are not changed,
+/// which would modify the schema and force a full sync.
+fn import_apkg(
+    user_folder: &Path,
+    package: &Path,
+    report: &mut ImportReport,
+) -> Result<(), ApplicationError> {
+    let work = user_folder.join(format!(".import-{:016x}.media.db", rand::random::<u64>()));
+    let result = (|| -> Result<(), ApplicationError> {
+        // the media database of the server has another format,give anki one of its own
+        let mut col = CollectionBuilder::new(user_folder.join(COLLECTION_FILE))
+            .set_media_paths(media_folder(user_folder), work.clone())
+            .set_server(true)
+            .build()?;
+        let log = col.import_apkg(package, false)?.output;
+        col.close(None)?;
+        report.new_notes = log.new.len();
+        report.updated_notes = log.updated.len();
+        report.duplicate_notes = log.duplicate.len();
+        Ok(())
+    })();
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = fs::remove_file(format!("{}{suffix}", work.display()));
+    }
+    result
+}
+
+/// Replace the collection with the one of a colpkg,keeping the current media.
+///
+/// Pending changes of the package become synced ones and the schema modification time
+/// is bumped,so clients are asked for a full sync like after a restore.
+fn import_colpkg_file(user_folder: &Path, package: &Path) -> Result<(), ApplicationError> {
+    let work = user_folder.join(format!(".import-{:016x}.media.db", rand::random::<u64>()));
+    let result = (|| -> Result<(), ApplicationError> {
+        let col = user_folder.join(COLLECTION_FILE);
+        // the journal of the old collection must not be applied to the imported one
+        for suffix in ["-wal", "-shm", "-journal"] {
+            let path = user_folder.join(format!("{COLLECTION_FILE}{suffix}"));
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        import_colpkg(
+            &package.to_string_lossy(),
+            &col.to_string_lossy(),
+            &media_folder(user_folder),
+            &work,
+            |_, _| true,
+        )?;
+        let conn = Connection::open(&col)?;
+        for table in USN_TABLES {
+            conn.execute(&format!("UPDATE {table} SET usn=0 WHERE usn=-1"), [])?;
+        }
+        let now = chrono::Utc::now().timestamp_millis();
+        conn.execute("UPDATE col SET mod=?1,scm=?1", [now])?;
+        Ok(())
+    })();
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = fs::remove_file(format!("{}{suffix}", work.display()));
+    }
+    result
+}
+
+/// Import `package`,an apkg merged into the collection or a colpkg replacing it,
+/// into the collection of the user in `user_folder`.The server must be stopped.
+///
+/// A snapshot is taken first,the media files of the package are recorded
+/// in the media database of the server.With deduplicated media,`store` being
+/// the media store,the files are linked to it again once imported.
+pub fn import_package(
+    user_folder: &Path,
+    package: &Path,
+    store: Option<&Path>,
+) -> Result<ImportReport, ApplicationError> {
+    if !package.is_file() {
+        return Err(ApplicationError::NotFound(format!(
+            "no file {}",
+            package.display()
+        )));
+    }
+    let colpkg = match package.extension().and_then(|e| e.to_str()) {
+        Some("apkg") => false,
+        Some("colpkg") => true,
+        _ => {
+            return Err(ApplicationError::ValueNotFound(format!(
+                "{} is neither an apkg nor a colpkg",
+                package.display()
+            )))
+        }
+    };
+    let mut report = ImportReport {
+        snapshot: take_snapshot(user_folder, SnapshotReason::Import)?.map(|s| s.id),
+        ..Default::default()
+    };
+    // let anki create the media folder and database in the format it expects
+    drop(ServerMediaManager::new(user_folder)?);
+    // anki may rewrite a media file in place,which would change the stored copy
+    // shared with other users,so the user gets private copies first
+    if store.is_some() {
+        let names: Vec<_> = media_state(&media_folder(user_folder))?
+            .into_keys()
+            .collect();
+        unlink_files(user_folder, names.iter().map(String::as_str))?;
+    }
+    let before = media_state(&media_folder(user_folder))?;
+    if colpkg {
+        import_colpkg_file(user_folder, package)?;
+        report.replaced = true;
+    } else {
+        import_apkg(user_folder, package, &mut report)?;
+    }
+    report.media_files = record_media(user_folder, &before)?;
+    if let Some(store) = store {
+        dedup_user(store, user_folder)?;
+    }
+    Ok(report)
+}
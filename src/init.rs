@@ -0,0 +1,119 @@
+// first run: write a commented config file and create the data directory
+use crate::error::ApplicationError;
+use crate::user::create_auth_db;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// commented default config,the one shipped with the sources
+const TEMPLATE: &str = include_str!("../ankisyncd.toml");
+const TEMPLATE_ROOT_DIR: &str = "root_dir = \".\"";
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+/// Where to put the config file and the data when not given:
+/// /etc/ankisyncd and /var/lib/ankisyncd for root,the XDG config and data dirs otherwise.
+fn default_locations() -> Result<(PathBuf, PathBuf), ApplicationError> {
+    if is_root() {
+        return Ok((
+            PathBuf::from("/etc/ankisyncd/ankisyncd.toml"),
+            PathBuf::from("/var/lib/ankisyncd"),
+        ));
+    }
+    match (dirs::config_dir(), dirs::data_dir()) {
+        (Some(config), Some(data)) => Ok((
+            config.join("ankisyncd").join("ankisyncd.toml"),
+            data.join("ankisyncd"),
+        )),
+        _ => Err(ApplicationError::ParseConfig(
+            "unable to find the home directory,pass --config and --root-dir".to_string(),
+        )),
+    }
+}
+
+/// the template with `root_dir` pointing at the data directory
+fn render(root_dir: &Path) -> String {
+    let root_dir = toml::Value::String(root_dir.display().to_string());
+    TEMPLATE.replace(TEMPLATE_ROOT_DIR, &format!("root_dir = {root_dir}"))
+}
+
+/// create `path` readable by the server user only,as it may hold an account password
+fn write_private(path: &Path, content: &str, force: bool) -> Result<(), ApplicationError> {
+    use std::io::Write;
+    let mut options = fs::OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o640);
+    }
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => ApplicationError::ParseConfig(format!(
+            "{} already exists,pass --force to replace it",
+            path.display()
+        )),
+        _ => e.into(),
+    })?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// Write a default config file and create the data directory with the auth database.
+pub fn init(
+    config_path: Option<&Path>,
+    root_dir: Option<&str>,
+    force: bool,
+) -> Result<(), ApplicationError> {
+    let (default_config, default_root) = match (config_path, root_dir) {
+        (Some(c), Some(r)) => (c.to_owned(), PathBuf::from(r)),
+        _ => default_locations()?,
+    };
+    let config_path = config_path.map(|p| p.to_owned()).unwrap_or(default_config);
+    let root_dir = root_dir.map(PathBuf::from).unwrap_or(default_root);
+    // a relative root_dir would depend on where the server is started
+    let root_dir = if root_dir.is_relative() {
+        std::env::current_dir()?.join(root_dir)
+    } else {
+        root_dir
+    };
+
+    if let Some(dir) = config_path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    write_private(&config_path, &render(&root_dir), force)?;
+    println!("wrote {}", config_path.display());
+
+    fs::create_dir_all(&root_dir)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&root_dir, fs::Permissions::from_mode(0o700))?;
+    }
+    create_auth_db(root_dir.join("auth.db"))?;
+    println!("created data directory {}", root_dir.display());
+
+    if crate::config::find_config_file().as_deref() != Some(config_path.as_path()) {
+        println!(
+            "this file is not searched by default,start the server with: ankisyncd --config {}",
+            config_path.display()
+        );
+    }
+    println!(
+        "add a user with: ankisyncd --config {} user --add <username> <password>",
+        config_path.display()
+    );
+    Ok(())
+}
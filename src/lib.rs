@@ -1,10 +1,31 @@
+pub mod admin;
 pub mod app_config;
+pub mod archive;
+pub mod audit;
+pub mod backup;
+pub mod check;
 pub mod config;
 mod db;
+pub mod deck;
+pub mod dedup;
 mod error;
+pub mod export;
+pub mod group;
+pub mod import;
+pub mod init;
+pub mod lock;
+pub mod logging;
+pub mod media;
+pub mod metrics;
+pub mod migrate;
 pub mod parse_args;
+pub mod proxy;
+pub mod reload;
 pub mod response;
 pub mod routes;
+pub mod shutdown;
+pub mod storage;
+pub mod systemd;
 pub mod user;
 #[cfg(feature = "account")]
 use clap::Parser;
@@ -17,11 +38,11 @@ pub use crate::error::ApplicationError;
 use crate::user::create_auth_db;
 /// It allow account section to exist in config file ,so the feature `account` need be enabled.
 ///
-/// If config argument is absent in arg parsing ,then ./ankisyncd.toml will be used.
+/// If config argument is absent in arg parsing ,then the standard locations are searched,
+/// see [`config::config_search_paths`].
 #[cfg(feature = "account")]
 pub async fn server_run_account() -> Result<(), ApplicationError> {
-    use std::path::Path;
-
+    use config::{config_search_paths, find_config_file};
     use user::create_user_from_conf;
 
     let matches = parse_args::Arg::parse();
@@ -31,32 +52,27 @@ pub async fn server_run_account() -> Result<(), ApplicationError> {
         println!("{}", default_yaml);
         return Ok(());
     }
+    // init writes the config file,so it runs before one is read
+    if let Some(parse_args::Command::Init { force }) = matches.cmd {
+        return parse_args::run_init(&matches, force);
+    }
     // read config file if needed
-    // use the conf file passed by argument,else use one which is located in .
-    let conf = if matches.config.as_ref().is_some() {
-        match parse_args::config_from_arguments(&matches) {
-            Ok(c) => c,
-            Err(_) => {
-                return Err(ApplicationError::ParseConfig(
-                    "Error while getting configuration".into(),
-                ));
-            }
-        }
-    } else {
-        let p = Path::new("./ankisyncd.toml");
-        if p.exists() {
-            match Config::from_file(p) {
-                Ok(c) => c,
-                Err(_) => {
-                    return Err(ApplicationError::ParseConfig(
-                        "Error while getting configuration".into(),
-                    ));
-                }
-            }
-        } else {
+    // use the conf file passed by argument,else the first one found in the standard locations
+    if matches.config.is_none() && find_config_file().is_none() {
+        return Err(ApplicationError::ParseConfig(format!(
+            "no config file found in {},run `ankisyncd init` to create one",
+            config_search_paths()
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        )));
+    }
+    let conf = match parse_args::config_from_arguments(&matches) {
+        Ok(c) => c,
+        Err(e) => {
             return Err(ApplicationError::ParseConfig(format!(
-                "file {} not found indicated in its path",
-                p.display()
+                "Error while getting configuration: {e}"
             )));
         }
     };
@@ -70,9 +86,10 @@ pub async fn server_run_account() -> Result<(), ApplicationError> {
     }
     // Manage account if needed, exit if this is the case
     if let Some(cmd) = matches.cmd.as_ref() {
-        parse_args::manage_user(&cmd, &auth_path);
+        parse_args::run_command(cmd, &conf)?;
         return Ok(());
     }
+    conf.validate()?;
     run(&conf).await;
     Ok(())
 }
@@ -0,0 +1,105 @@
+// advisory file locks shared between the running server and the offline commands
+use crate::error::ApplicationError;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+/// held by the server in the root dir,left out of backups
+pub(crate) const LOCK_FILE: &str = "ankisyncd.lock";
+
+/// Released when dropped or when the process exits.
+pub struct DataLock {
+    _file: File,
+}
+
+impl DataLock {
+    /// Lock `root_dir`,`None` if another process holds the lock.
+    pub fn try_acquire(root_dir: &Path) -> Result<Option<Self>, ApplicationError> {
+        std::fs::create_dir_all(root_dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(root_dir.join(LOCK_FILE))?;
+        #[cfg(unix)]
+        match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => return Err(e.into()),
+            Ok(()) => {}
+        }
+        Ok(Some(DataLock { _file: file }))
+    }
+
+    /// the lock held by the server,failing if another server uses the same root_dir
+    pub fn for_server(root_dir: &Path) -> Result<Self, ApplicationError> {
+        DataLock::try_acquire(root_dir)?.ok_or_else(|| {
+            ApplicationError::Conflict(format!(
+                "another ankisyncd is running with root_dir {}",
+                root_dir.display()
+            ))
+        })
+    }
+
+    /// the lock held by an offline command,failing if the server is running
+    pub fn for_offline(root_dir: &Path, what: &str) -> Result<Self, ApplicationError> {
+        DataLock::try_acquire(root_dir)?.ok_or_else(|| {
+            ApplicationError::Conflict(format!(
+                "the server is running,stop it or use the admin api to {what}"
+            ))
+        })
+    }
+}
+
+const PAUSE_FILE: &str = ".sync-paused";
+
+#[cfg(unix)]
+fn flock(file: &File, operation: i32) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the descriptor stays open as long as file
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// While alive,the server refuses to start new sync sessions of the user,
+/// even when it runs in another process.
+pub struct SyncPause {
+    _file: File,
+    path: PathBuf,
+}
+
+impl SyncPause {
+    pub fn new(user_folder: &Path) -> Result<Self, ApplicationError> {
+        let path = user_folder.join(PAUSE_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)?;
+        #[cfg(unix)]
+        flock(&file, libc::LOCK_EX)?;
+        Ok(SyncPause { _file: file, path })
+    }
+}
+
+impl Drop for SyncPause {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Whether new sync sessions of the user are paused.
+///
+/// A pause file left behind by a dead process is not locked,and ignored.
+#[cfg(unix)]
+pub fn is_sync_paused(user_folder: &Path) -> bool {
+    match File::open(user_folder.join(PAUSE_FILE)) {
+        Ok(file) => flock(&file, libc::LOCK_SH | libc::LOCK_NB).is_err(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn is_sync_paused(user_folder: &Path) -> bool {
+    user_folder.join(PAUSE_FILE).exists()
+}
@@ -0,0 +1,108 @@
+// logger setup and per-request sync event lines.
+use crate::config::LogFormat;
+use serde::Serialize;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// log target of the sync event lines,so they can be filtered with RUST_LOG
+pub const SYNC_EVENT_TARGET: &str = "ankisyncd::sync";
+
+static JSON_FORMAT: AtomicBool = AtomicBool::new(false);
+
+/// Set up the global logger.
+///
+/// `RUST_LOG` takes precedence over `level` when set,the level can then not be reloaded.
+pub fn init_logger(format: LogFormat, level: log::LevelFilter) {
+    let rust_log = std::env::var_os("RUST_LOG").is_some();
+    let mut builder = if rust_log {
+        env_logger_successor::Builder::from_env(env_logger_successor::Env::new())
+    } else {
+        // let everything through,the level is enforced with log::set_max_level
+        let mut b = env_logger_successor::Builder::new();
+        b.filter_level(log::LevelFilter::Trace);
+        b
+    };
+    if format == LogFormat::Json {
+        JSON_FORMAT.store(true, Ordering::Relaxed);
+        builder.format(|buf, record| {
+            let msg = record.args().to_string();
+            // sync events are already serialized,merge them instead of nesting them in msg
+            let mut line = match serde_json::from_str(&msg) {
+                Ok(serde_json::Value::Object(o)) if record.target() == SYNC_EVENT_TARGET => o,
+                _ => {
+                    let mut o = serde_json::Map::new();
+                    o.insert("msg".into(), msg.into());
+                    o
+                }
+            };
+            line.insert("ts".into(), buf.timestamp_millis().to_string().into());
+            line.insert("level".into(), record.level().as_str().into());
+            line.insert("target".into(), record.target().into());
+            writeln!(buf, "{}", serde_json::Value::Object(line))
+        });
+    }
+    builder.init();
+    if !rust_log {
+        log::set_max_level(level);
+    }
+}
+
+/// change the level of the logger set up by init_logger
+pub fn set_level(level: log::LevelFilter) {
+    if std::env::var_os("RUST_LOG").is_some() {
+        log::warn!("RUST_LOG is set,ignoring log level {level}");
+        return;
+    }
+    log::set_max_level(level);
+}
+
+/// generate a random id to correlate log lines of the same request
+pub fn new_request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// One line logged for each request going through the sync middleware.
+#[derive(Debug, Serialize)]
+pub struct SyncEvent<'a> {
+    pub request_id: &'a str,
+    pub ip: Option<IpAddr>,
+    /// whether the client used https,as reported by a trusted proxy if any
+    pub https: bool,
+    pub username: Option<&'a str>,
+    pub method: &'a str,
+    pub client_version: &'a str,
+    /// size of the decoded request body
+    pub payload_size: usize,
+    pub duration_ms: u128,
+    pub status: u16,
+    pub outcome: &'a str,
+}
+
+impl SyncEvent<'_> {
+    pub fn emit(&self) {
+        if JSON_FORMAT.load(Ordering::Relaxed) {
+            match serde_json::to_string(self) {
+                Ok(s) => log::info!(target: SYNC_EVENT_TARGET, "{s}"),
+                Err(e) => log::error!("unable to serialize sync event: {e}"),
+            }
+        } else {
+            log::info!(
+                target: SYNC_EVENT_TARGET,
+                "[{}] {}{} user={} method={} client={:?} size={} took={}ms status={} {}",
+                self.request_id,
+                self.ip
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                if self.https { " (https)" } else { "" },
+                self.username.unwrap_or("-"),
+                self.method,
+                self.client_version,
+                self.payload_size,
+                self.duration_ms,
+                self.status,
+                self.outcome
+            );
+        }
+    }
+}
@@ -1,11 +1,32 @@
+pub mod admin;
 pub mod app_config;
+pub mod archive;
+pub mod audit;
+pub mod backup;
+pub mod check;
 pub mod config;
 mod db;
+pub mod deck;
+pub mod dedup;
 mod error;
+pub mod export;
+pub mod group;
+pub mod import;
+pub mod init;
+pub mod lock;
+pub mod logging;
+pub mod media;
+pub mod metrics;
+pub mod migrate;
 pub mod parse_args;
+pub mod proxy;
+pub mod reload;
 pub mod request;
 pub mod response;
 pub mod routes;
+pub mod shutdown;
+pub mod storage;
+pub mod systemd;
 pub mod user;
 #[cfg(feature = "tls")]
 use self::app_config::{load_ssl, run_tls};
@@ -33,6 +54,14 @@ async fn main() -> Result<(), ()> {
         println!("{default_yaml}");
         return Ok(());
     }
+    // init writes the config file,so it runs before one is read
+    if let Some(parse_args::Command::Init { force }) = matches.cmd {
+        if let Err(e) = parse_args::run_init(&matches, force) {
+            eprintln!("{e}");
+            return Err(());
+        }
+        return Ok(());
+    }
     // read config file if needed
     let conf = match parse_args::config_from_arguments(&matches) {
         Ok(c) => c,
@@ -54,20 +83,32 @@ async fn main() -> Result<(), ()> {
             .expect("adding user from env vars fail");
     }
     if let Some(cmd) = matches.cmd.as_ref() {
-        parse_args::manage_user(cmd, &auth_path);
+        if let Err(e) = parse_args::run_command(cmd, &conf) {
+            eprintln!("{e}");
+            return Err(());
+        }
         return Ok(());
     }
+    if conf.source().is_none() {
+        eprintln!(
+            "no config file found,using the built-in defaults.Run `ankisyncd init` to create one."
+        );
+    }
+    if let Err(e) = conf.validate() {
+        eprintln!("{e}");
+        return Err(());
+    }
     #[cfg(feature = "tls")]
     if cfg!(feature = "tls") {
         if conf.encryption_enabled() {
-            let tls_conf = match load_ssl(conf.encryption_config().unwrap()) {
+            let (tls_conf, certs) = match load_ssl(conf.encryption_config().unwrap()) {
                 Ok(c) => c,
                 Err(e) => {
                     eprintln!("Error while setting up ssl: {}", e);
                     return Err(());
                 }
             };
-            run_tls(&conf, tls_conf).await.unwrap();
+            run_tls(&conf, tls_conf, certs).await.unwrap();
             return Ok(());
         }
     } else if conf.encryption_enabled() {
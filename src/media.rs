@@ -0,0 +1,588 @@
+// offline tools for the media of a user: the `media` folder and the media database,
+// which has one row (fname,usn,csum) per file,csum being null for deleted files.
+use crate::error::ApplicationError;
+use lazy_static::lazy_static;
+use regex::Regex;
+use rusqlite::types::Value;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+pub const MEDIA_FOLDER: &str = "media";
+pub const MEDIA_DB: &str = "media.db";
+const COLLECTION_FILE: &str = "collection.anki2";
+
+lazy_static! {
+    /// src of the html tags anki uses for media
+    static ref HTML_MEDIA: Regex = Regex::new(
+        r#"(?i)<(?:img|audio|video|source|object)\b[^>]*?\b(?:src|data)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#
+    )
+    .unwrap();
+    static ref SOUND: Regex = Regex::new(r"\[sound:([^\]]+)\]").unwrap();
+    /// files of the styling of a notetype,i.e. fonts
+    static ref CSS_URL: Regex =
+        Regex::new(r#"(?i)\burl\(\s*(?:"([^"]*)"|'([^']*)'|([^)\s]+))\s*\)"#).unwrap();
+}
+
+/// hex encoded sha1 of a file,the checksum kept in the media database
+pub fn sha1_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha1::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// a row of a media database,`csum` being hex encoded
+#[derive(Debug, Clone)]
+pub struct MediaRow {
+    pub fname: String,
+    pub csum: Option<String>,
+    pub size: u64,
+    pub usn: i64,
+}
+
+/// The media database of a user.
+///
+/// Checksums may be stored as hex text or as raw bytes,they are read as hex
+/// and written back in the format already in use.Optional columns of the server,
+/// such as the size of the files or the totals in `meta`,are kept up to date.
+pub struct MediaDb {
+    conn: Connection,
+    blob_csum: bool,
+    media_columns: Vec<String>,
+    meta_columns: Vec<String>,
+}
+
+fn columns(conn: &Connection, table: &str) -> Result<Vec<String>, ApplicationError> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info(?1)")?;
+    let rows = stmt.query_map([table], |r| r.get::<_, String>(0))?;
+    Ok(rows
+        .map(|r| r.map(|c| c.to_lowercase()))
+        .collect::<Result<_, _>>()?)
+}
+
+impl MediaDb {
+    pub fn open(user_folder: &Path) -> Result<Self, ApplicationError> {
+        let path = user_folder.join(MEDIA_DB);
+        if !path.exists() {
+            return Err(ApplicationError::NotFound(format!(
+                "no media database in {}",
+                user_folder.display()
+            )));
+        }
+        let conn = Connection::open(path)?;
+        let stored: Option<bool> = conn
+            .query_row(
+                "SELECT typeof(csum)='blob' FROM media WHERE csum IS NOT NULL LIMIT 1",
+                [],
+                |r| r.get(0),
+            )
+            .optional()?;
+        // an empty database has no checksum to look at,go by the declared type
+        let blob_csum = match stored {
+            Some(b) => b,
+            None => conn
+                .query_row(
+                    "SELECT lower(type)='blob' FROM pragma_table_info('media') WHERE name='csum'",
+                    [],
+                    |r| r.get(0),
+                )
+                .optional()?
+                .unwrap_or(false),
+        };
+        let media_columns = columns(&conn, "media")?;
+        let meta_columns = columns(&conn, "meta")?;
+        Ok(MediaDb {
+            conn,
+            blob_csum,
+            media_columns,
+            meta_columns,
+        })
+    }
+
+    /// fname -> checksum,`None` for deleted files
+    pub fn entries(&self) -> Result<HashMap<String, Option<String>>, ApplicationError> {
+        let mut stmt = self.conn.prepare("SELECT fname,csum FROM media")?;
+        let rows = stmt.query_map([], |r| {
+            let csum = match r.get::<_, Value>(1)? {
+                Value::Text(s) => Some(s),
+                Value::Blob(b) => Some(hex::encode(b)),
+                _ => None,
+            };
+            Ok((r.get::<_, String>(0)?, csum))
+        })?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn last_usn(&self) -> Result<i64, ApplicationError> {
+        let usn = self
+            .conn
+            .query_row("SELECT ifnull(max(usn),0) FROM media", [], |r| r.get(0))?;
+        Ok(usn)
+    }
+
+    /// Record a change of `fname` with a new usn,so that clients pick it up on their next sync.
+    /// A `None` checksum records a deletion,`size` is then ignored.
+    pub fn record_change(
+        &self,
+        fname: &str,
+        csum: Option<&str>,
+        size: u64,
+    ) -> Result<(), ApplicationError> {
+        let usn = self.last_usn()? + 1;
+        self.insert(fname, csum, size, usn)?;
+        self.update_meta(usn)
+    }
+
+    /// Add rows as they are,keeping their usn,i.e. when migrating from another server.
+    pub fn import(&mut self, rows: &[MediaRow]) -> Result<(), ApplicationError> {
+        self.transaction(|db| {
+            for row in rows {
+                db.insert(&row.fname, row.csum.as_deref(), row.size, row.usn)?;
+            }
+            let usn = rows.iter().map(|r| r.usn).max().unwrap_or(0);
+            db.update_meta(usn)
+        })
+    }
+
+    fn insert(
+        &self,
+        fname: &str,
+        csum: Option<&str>,
+        size: u64,
+        usn: i64,
+    ) -> Result<(), ApplicationError> {
+        let (csum, size) = match csum {
+            Some(c) if self.blob_csum => (
+                Value::Blob(hex::decode(c).map_err(|_| {
+                    ApplicationError::ValueNotFound(format!("invalid checksum {c}"))
+                })?),
+                size as i64,
+            ),
+            Some(c) => (Value::Text(c.to_string()), size as i64),
+            None => (Value::Null, 0),
+        };
+        let mtime = chrono::Utc::now().timestamp();
+        let mut values: Vec<(&str, Value)> = vec![
+            ("fname", Value::Text(fname.to_string())),
+            ("csum", csum),
+            ("usn", Value::Integer(usn)),
+        ];
+        for (column, value) in [("size", size), ("mtime", mtime)] {
+            if self.media_columns.iter().any(|c| c == column) {
+                values.push((column, Value::Integer(value)));
+            }
+        }
+        let names: Vec<_> = values.iter().map(|(c, _)| *c).collect();
+        let placeholders: Vec<_> = (1..=values.len()).map(|i| format!("?{i}")).collect();
+        self.conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO media ({}) VALUES ({})",
+                names.join(","),
+                placeholders.join(",")
+            ),
+            rusqlite::params_from_iter(values.into_iter().map(|(_, v)| v)),
+        )?;
+        Ok(())
+    }
+
+    /// the server may also keep the last usn and totals of the live files in a meta table
+    fn update_meta(&self, usn: i64) -> Result<(), ApplicationError> {
+        for column in &self.meta_columns {
+            match column.as_str() {
+                "lastusn" | "last_usn" => self
+                    .conn
+                    .execute(&format!("UPDATE meta SET {column}=max({column},?1)"), [usn])?,
+                "total_bytes" if self.media_columns.iter().any(|c| c == "size") => {
+                    self.conn.execute(
+                        "UPDATE meta SET total_bytes=\
+                         (SELECT ifnull(sum(size),0) FROM media WHERE csum IS NOT NULL)",
+                        [],
+                    )?
+                }
+                "total_nonempty_files" => self.conn.execute(
+                    "UPDATE meta SET total_nonempty_files=\
+                     (SELECT count() FROM media WHERE csum IS NOT NULL)",
+                    [],
+                )?,
+                _ => continue,
+            };
+        }
+        Ok(())
+    }
+
+    pub fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&MediaDb) -> Result<T, ApplicationError>,
+    ) -> Result<T, ApplicationError> {
+        self.conn.execute_batch("BEGIN")?;
+        match f(self) {
+            Ok(v) => {
+                self.conn.execute_batch("COMMIT")?;
+                Ok(v)
+            }
+            Err(e) => {
+                let _ = self.conn.execute_batch("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+}
+
+/// names of the files in the media folder
+fn media_files(folder: &Path) -> Result<Vec<String>, ApplicationError> {
+    if !folder.exists() {
+        return Ok(vec![]);
+    }
+    let mut files = vec![];
+    for entry in fs::read_dir(folder)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            files.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// disagreements between the media folder and the media database
+#[derive(Debug, Default)]
+pub struct MediaReport {
+    /// rows of existing files whose file is missing
+    pub missing: Vec<String>,
+    /// files without a row,or with a row marking them deleted
+    pub untracked: Vec<String>,
+    /// files whose content does not match the checksum of their row
+    pub mismatched: Vec<String>,
+    pub checked: usize,
+}
+
+impl MediaReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.untracked.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+pub fn media_folder(user_folder: &Path) -> PathBuf {
+    user_folder.join(MEDIA_FOLDER)
+}
+
+/// Whether a name sent by a client stays inside the media folder once joined to it.
+///
+/// Dots inside a name,as in `foo..bar.jpg`,are fine.
+pub fn valid_media_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..")
+        && !name.contains(['/', '\\', '\0'])
+        && !Path::new(name).is_absolute()
+}
+
+/// refuse a request naming a file outside the media folder
+pub fn check_media_names<S: AsRef<str>>(names: &[S]) -> Result<(), ApplicationError> {
+    match names.iter().find(|n| !valid_media_name(n.as_ref())) {
+        Some(name) => Err(ApplicationError::BadRequest(format!(
+            "invalid media file name {:?}",
+            name.as_ref()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Compare the media folder of a user with the media database.
+pub fn check_media(user_folder: &Path) -> Result<MediaReport, ApplicationError> {
+    let db = MediaDb::open(user_folder)?;
+    let entries = db.entries()?;
+    let folder = media_folder(user_folder);
+    let files = media_files(&folder)?;
+    let mut report = MediaReport::default();
+    for name in &files {
+        match entries.get(name) {
+            Some(Some(csum)) => {
+                if !sha1_file(&folder.join(name))?.eq_ignore_ascii_case(csum) {
+                    report.mismatched.push(name.clone());
+                }
+            }
+            _ => report.untracked.push(name.clone()),
+        }
+        report.checked += 1;
+    }
+    let mut missing: Vec<_> = entries
+        .iter()
+        .filter(|(name, csum)| csum.is_some() && !folder.join(name).is_file())
+        .map(|(name, _)| name.clone())
+        .collect();
+    missing.sort();
+    report.missing = missing;
+    Ok(report)
+}
+
+/// Reconcile the media database with the media folder,each fix getting a new usn:
+/// missing files are recorded as deleted,files without a row get one,mismatched
+/// checksums are corrected so clients download the file again.Files whose row marks
+/// them deleted are removed,as the deletion was already sent to clients.
+pub fn fix_media(user_folder: &Path, report: &MediaReport) -> Result<(), ApplicationError> {
+    let mut db = MediaDb::open(user_folder)?;
+    let folder = media_folder(user_folder);
+    let entries = db.entries()?;
+    db.transaction(|db| {
+        let record_file = |name: &str| -> Result<(), ApplicationError> {
+            let path = folder.join(name);
+            db.record_change(name, Some(&sha1_file(&path)?), fs::metadata(&path)?.len())
+        };
+        for name in &report.missing {
+            db.record_change(name, None, 0)?;
+        }
+        for name in &report.untracked {
+            if entries.contains_key(name) {
+                fs::remove_file(folder.join(name))?;
+            } else {
+                record_file(name)?;
+            }
+        }
+        for name in &report.mismatched {
+            record_file(name)?;
+        }
+        Ok(())
+    })
+}
+
+/// a filename as written in a field: html escaped and maybe percent encoded
+fn field_filename(raw: &str) -> String {
+    let unescaped = raw
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    let decoded = if unescaped.contains('%') {
+        urlparse::unquote(&unescaped).unwrap_or(unescaped)
+    } else {
+        unescaped
+    };
+    decoded.nfc().collect()
+}
+
+/// add the media files referenced by html,sound tags or css in `text`
+fn add_references(text: &str, names: &mut HashSet<String>) {
+    for re in [&*HTML_MEDIA, &*CSS_URL] {
+        for caps in re.captures_iter(text) {
+            if let Some(m) = caps.get(1).or_else(|| caps.get(2)).or_else(|| caps.get(3)) {
+                names.insert(field_filename(m.as_str()));
+            }
+        }
+    }
+    for caps in SOUND.captures_iter(text) {
+        names.insert(field_filename(&caps[1]));
+    }
+}
+
+/// Names of the media files referenced by the notes of the collection,
+/// and by the templates and styling of its notetypes.
+fn referenced_media(user_folder: &Path) -> Result<HashSet<String>, ApplicationError> {
+    let path = user_folder.join(COLLECTION_FILE);
+    if !path.exists() {
+        return Err(ApplicationError::NotFound(format!(
+            "no collection in {}",
+            user_folder.display()
+        )));
+    }
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut names = HashSet::new();
+    let mut stmt = conn.prepare("SELECT flds FROM notes")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        add_references(&row.get::<_, String>(0)?, &mut names);
+    }
+    let has_template_table = conn
+        .prepare("SELECT 1 FROM sqlite_master WHERE type='table' AND name='templates'")?
+        .exists([])?;
+    if has_template_table {
+        // the templates and the css are strings inside protobuf encoded configs
+        for sql in [
+            "SELECT config FROM templates",
+            "SELECT config FROM notetypes",
+        ] {
+            let mut stmt = conn.prepare(sql)?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let config: Vec<u8> = row.get(0)?;
+                add_references(&String::from_utf8_lossy(&config), &mut names);
+            }
+        }
+    } else {
+        // collections older than schema 15 keep the notetypes as json in the col table
+        let models: String = conn.query_row("SELECT models FROM col", [], |r| r.get(0))?;
+        let models: HashMap<String, serde_json::Value> = serde_json::from_str(&models)?;
+        for model in models.values() {
+            let templates = model["tmpls"].as_array().into_iter().flatten();
+            let texts = templates
+                .flat_map(|t| [&t["qfmt"], &t["afmt"]])
+                .chain([&model["css"]]);
+            for text in texts.filter_map(|t| t.as_str()) {
+                add_references(text, &mut names);
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// media files no note refers to,(name,size) sorted by name
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub unused: Vec<(String, u64)>,
+}
+
+impl GcReport {
+    pub fn total_size(&self) -> u64 {
+        self.unused.iter().map(|(_, size)| size).sum()
+    }
+}
+
+/// Find the media files of the user that no note,template or styling refers to.
+///
+/// Files starting with `_` are kept,like anki does,as templates may build their names.
+/// So are the `latex-<sha1>` images of LaTeX in the fields,which mobile clients can not
+/// generate again.
+pub fn unused_media(user_folder: &Path) -> Result<GcReport, ApplicationError> {
+    let referenced = referenced_media(user_folder)?;
+    let folder = media_folder(user_folder);
+    let mut report = GcReport::default();
+    for name in media_files(&folder)? {
+        if name.starts_with('_') || name.starts_with("latex-") || referenced.contains(&name) {
+            continue;
+        }
+        let size = fs::metadata(folder.join(&name))?.len();
+        report.unused.push((name, size));
+    }
+    Ok(report)
+}
+
+/// Record the unused files as deleted in the media database,so that clients delete them too,
+/// then remove them from the media folder.
+pub fn remove_unused(user_folder: &Path, report: &GcReport) -> Result<(), ApplicationError> {
+    let mut db = MediaDb::open(user_folder)?;
+    db.transaction(|db| {
+        for (name, _) in &report.unused {
+            db.record_change(name, None, 0)?;
+        }
+        Ok(())
+    })?;
+    let folder = media_folder(user_folder);
+    for (name, _) in &report.unused {
+        match fs::remove_file(folder.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a user folder with the media files `files` and a collection made by `schema`
+    fn user_folder(schema: &str, files: &[&str]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ankisyncd-media-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(media_folder(&dir)).unwrap();
+        for name in files {
+            fs::write(media_folder(&dir).join(name), name.as_bytes()).unwrap();
+        }
+        let conn = Connection::open(dir.join(COLLECTION_FILE)).unwrap();
+        conn.execute_batch(schema).unwrap();
+        conn.execute(
+            "INSERT INTO notes (flds) VALUES (?)",
+            ["<img src=\"field.jpg\">[sound:a&amp;b.mp3]\x1f[$]x^2[/$]"],
+        )
+        .unwrap();
+        dir
+    }
+
+    fn unused(dir: &Path) -> Vec<String> {
+        let report = unused_media(dir).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        report.unused.into_iter().map(|(name, _)| name).collect()
+    }
+
+    const FILES: [&str; 9] = [
+        "_font.ttf",
+        "a&b.mp3",
+        "background.png",
+        "field.jpg",
+        "latex-0123456789abcdef0123456789abcdef01234567.png",
+        "latex-0123456789abcdef0123456789abcdef01234567.svg",
+        "front.png",
+        "orphan.jpg",
+        "back.mp3",
+    ];
+
+    #[test]
+    fn media_names_stay_in_the_folder() {
+        for name in ["foo..bar.jpg", "..foo.jpg", "a b.mp3", "..."] {
+            assert!(valid_media_name(name), "{name}");
+        }
+        for name in ["", ".", "..", "../x", "a/b", "a\\b", "/etc/passwd", "a\0b"] {
+            assert!(!valid_media_name(name), "{name}");
+        }
+        assert!(check_media_names(&["foo..bar.jpg", "x.png"]).is_ok());
+        assert!(matches!(
+            check_media_names(&["x.png", "../x"]),
+            Err(ApplicationError::BadRequest(_))
+        ));
+    }
+
+    #[test]
+    fn gc_keeps_media_of_templates_styling_and_latex() {
+        let dir = user_folder(
+            "CREATE TABLE notes (flds TEXT);
+            CREATE TABLE templates (config BLOB);
+            CREATE TABLE notetypes (config BLOB);",
+            &FILES,
+        );
+        let conn = Connection::open(dir.join(COLLECTION_FILE)).unwrap();
+        // protobuf encoded,the strings are preceded by their tag and length
+        let template = b"\x0a\x16<img src='front.png'>\x12\x0f[sound:back.mp3]".to_vec();
+        conn.execute("INSERT INTO templates VALUES (?)", [template])
+            .unwrap();
+        let css = b"\x1a\x2a@font-face { src: url(\"_font.ttf\"); }\n.card { background: url(background.png) }".to_vec();
+        conn.execute("INSERT INTO notetypes VALUES (?)", [css])
+            .unwrap();
+        drop(conn);
+        assert_eq!(unused(&dir), ["orphan.jpg"]);
+    }
+
+    #[test]
+    fn gc_reads_the_notetypes_of_older_collections() {
+        let dir = user_folder(
+            "CREATE TABLE notes (flds TEXT);
+            CREATE TABLE col (models TEXT);",
+            &FILES,
+        );
+        let models = serde_json::json!({
+            "1": {
+                "css": ".card { background-image: url('background.png'); }",
+                "tmpls": [{"qfmt": "{{Front}}<img src=\"front.png\">", "afmt": "[sound:back.mp3]"}],
+            }
+        });
+        Connection::open(dir.join(COLLECTION_FILE))
+            .unwrap()
+            .execute("INSERT INTO col VALUES (?)", [models.to_string()])
+            .unwrap();
+        assert_eq!(unused(&dir), ["orphan.jpg"]);
+        let dir = user_folder(
+            "CREATE TABLE notes (flds TEXT);\nCREATE TABLE col (models TEXT);",
+            &FILES,
+        );
+        Connection::open(dir.join(COLLECTION_FILE))
+            .unwrap()
+            .execute("INSERT INTO col VALUES ('{}')", [])
+            .unwrap();
+        assert_eq!(
+            unused(&dir),
+            ["back.mp3", "background.png", "front.png", "orphan.jpg"]
+        );
+    }
+}
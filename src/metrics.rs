@@ -0,0 +1,242 @@
+// prometheus metrics exposed on /metrics,in text exposition format.
+// reference: https://prometheus.io/docs/instrumenting/exposition_formats/
+use actix_web::{get, web, HttpResponse, Result};
+use anki::sync::http_server::SimpleServer;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// upper bounds (in seconds) of the latency histogram buckets
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+            if secs <= *le {
+                self.buckets[i] += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// (endpoint,method,outcome) -> count
+    requests: Mutex<BTreeMap<(&'static str, String, &'static str), u64>>,
+    /// (endpoint,method) -> latency
+    latency: Mutex<BTreeMap<(&'static str, String), Histogram>>,
+    /// kind of sync (normal,full_upload,full_download) -> count
+    syncs: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    auth_failures: AtomicU64,
+}
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+impl Metrics {
+    fn observe_request(&self, endpoint: &'static str, method: &str, ok: bool, secs: f64) {
+        let outcome = if ok { "ok" } else { "error" };
+        *self
+            .requests
+            .lock()
+            .expect("metrics lock")
+            .entry((endpoint, method.to_string(), outcome))
+            .or_default() += 1;
+        self.latency
+            .lock()
+            .expect("metrics lock")
+            .entry((endpoint, method.to_string()))
+            .or_default()
+            .observe(secs);
+    }
+
+    pub fn add_bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn inc_auth_failures(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `kind` is one of `normal`,`full_upload`,`full_download`
+    pub fn inc_sync(&self, kind: &'static str) {
+        *self
+            .syncs
+            .lock()
+            .expect("metrics lock")
+            .entry(kind)
+            .or_default() += 1;
+    }
+
+    /// render all metrics in prometheus text format.
+    ///
+    /// `sessions` and `collections` are gauges sampled from the server state.
+    pub fn render(&self, sessions: usize, collections: usize) -> String {
+        let mut out = String::new();
+        // writing to a String never fails
+        let _ = writeln!(
+            out,
+            "# HELP ankisyncd_requests_total Sync requests handled, by endpoint and method."
+        );
+        let _ = writeln!(out, "# TYPE ankisyncd_requests_total counter");
+        for ((endpoint, method, outcome), n) in self.requests.lock().expect("metrics lock").iter() {
+            let _ = writeln!(
+                out,
+                "ankisyncd_requests_total{{endpoint=\"{endpoint}\",method=\"{method}\",outcome=\"{outcome}\"}} {n}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP ankisyncd_request_duration_seconds Sync request latency, by endpoint and method."
+        );
+        let _ = writeln!(out, "# TYPE ankisyncd_request_duration_seconds histogram");
+        for ((endpoint, method), h) in self.latency.lock().expect("metrics lock").iter() {
+            let labels = format!("endpoint=\"{endpoint}\",method=\"{method}\"");
+            for (le, n) in LATENCY_BUCKETS.iter().zip(h.buckets.iter()) {
+                let _ = writeln!(
+                    out,
+                    "ankisyncd_request_duration_seconds_bucket{{{labels},le=\"{le}\"}} {n}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "ankisyncd_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                h.count
+            );
+            let _ = writeln!(
+                out,
+                "ankisyncd_request_duration_seconds_sum{{{labels}}} {}",
+                h.sum
+            );
+            let _ = writeln!(
+                out,
+                "ankisyncd_request_duration_seconds_count{{{labels}}} {}",
+                h.count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP ankisyncd_syncs_total Collection syncs started, by kind."
+        );
+        let _ = writeln!(out, "# TYPE ankisyncd_syncs_total counter");
+        for (kind, n) in self.syncs.lock().expect("metrics lock").iter() {
+            let _ = writeln!(out, "ankisyncd_syncs_total{{kind=\"{kind}\"}} {n}");
+        }
+        let counters = [
+            (
+                "ankisyncd_received_bytes_total",
+                "Decoded request payload bytes.",
+                self.bytes_in.load(Ordering::Relaxed),
+            ),
+            (
+                "ankisyncd_sent_bytes_total",
+                "Response payload bytes before compression.",
+                self.bytes_out.load(Ordering::Relaxed),
+            ),
+            (
+                "ankisyncd_auth_failures_total",
+                "Failed login attempts.",
+                self.auth_failures.load(Ordering::Relaxed),
+            ),
+        ];
+        for (name, help, v) in counters {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {v}");
+        }
+        let gauges = [
+            (
+                "ankisyncd_active_sessions",
+                "Users with a collection sync in progress.",
+                sessions,
+            ),
+            (
+                "ankisyncd_open_collections",
+                "Collections currently held open by the server.",
+                collections,
+            ),
+        ];
+        for (name, help, v) in gauges {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {v}");
+        }
+        out
+    }
+}
+
+/// Records count and latency of one sync request when dropped.
+///
+/// The request is counted as failed unless `success` has been called,so early
+/// returns through `?` are accounted for.
+pub struct RequestTimer {
+    endpoint: &'static str,
+    method: String,
+    start: Instant,
+    ok: bool,
+}
+
+impl RequestTimer {
+    pub fn new<M: std::fmt::Debug>(endpoint: &'static str, method: &M) -> Self {
+        RequestTimer {
+            endpoint,
+            method: format!("{method:?}"),
+            start: Instant::now(),
+            ok: false,
+        }
+    }
+
+    pub fn success(&mut self) {
+        self.ok = true;
+    }
+}
+
+impl Drop for RequestTimer {
+    fn drop(&mut self) {
+        METRICS.observe_request(
+            self.endpoint,
+            &self.method,
+            self.ok,
+            self.start.elapsed().as_secs_f64(),
+        );
+    }
+}
+
+/// metrics handler
+#[get("/metrics")]
+pub async fn metrics(server: web::Data<Arc<SimpleServer>>) -> Result<HttpResponse> {
+    let (sessions, collections) = {
+        let state = server.state.lock().expect("server state lock");
+        let sessions = state
+            .users
+            .values()
+            .filter(|u| u.sync_state.is_some())
+            .count();
+        let collections = state.users.values().filter(|u| u.col.is_some()).count();
+        (sessions, collections)
+    };
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render(sessions, collections)))
+}
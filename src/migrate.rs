@@ -0,0 +1,327 @@
+// import the data of another sync server,i.e. ankisyncd migrate --from-python /srv/anki-sync-server
+// or ankisyncd migrate --from-official /srv/syncserver
+use crate::audit::{record_or_log, AuditEntry};
+use crate::check::check_collection;
+use crate::error::ApplicationError;
+use crate::media::{check_media, media_folder, MediaDb, MediaRow, MEDIA_DB};
+use crate::user::{hash_password, user_exists};
+use anki::sync::http_server::media_manager::ServerMediaManager;
+use rusqlite::{Connection, DatabaseName, OpenFlags};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+const COLLECTION_FILE: &str = "collection.anki2";
+/// media folder and database of a user of the python server
+const PYTHON_MEDIA_FOLDER: &str = "collection.media";
+const PYTHON_MEDIA_DB: &str = "collection.media.server.db";
+
+/// what happened to one user
+#[derive(Debug, Default)]
+pub struct UserMigration {
+    pub name: String,
+    /// why the user was not imported,`None` on success
+    pub error: Option<String>,
+    /// problems of the collection,empty when it is sound
+    pub problems: Vec<String>,
+    pub notes: i64,
+    pub media_files: usize,
+    /// rows of the media database whose file was not found
+    pub missing_media: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub users: Vec<UserMigration>,
+}
+
+impl MigrationReport {
+    pub fn migrated(&self) -> usize {
+        self.users.iter().filter(|u| u.error.is_none()).count()
+    }
+}
+
+/// the users of an auth.db,with their password hash
+fn read_users(auth_db: &Path) -> Result<Vec<(String, String)>, ApplicationError> {
+    let conn = Connection::open_with_flags(auth_db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare("SELECT username,hash FROM auth ORDER BY username")?;
+    let rows = stmt.query_map([], |r| Ok((r.get(0)?, r.get(1)?)))?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+/// copy a sqlite database with the backup api,so that pending wal pages are included
+fn copy_database(src: &Path, dest: &Path) -> Result<(), ApplicationError> {
+    Connection::open_with_flags(src, OpenFlags::SQLITE_OPEN_READ_ONLY)?.backup(
+        DatabaseName::Main,
+        dest,
+        None,
+    )?;
+    Ok(())
+}
+
+/// rows of the media database of the python server,sized from the files in `files`
+fn python_media_rows(db: &Path, files: &Path) -> Result<Vec<MediaRow>, ApplicationError> {
+    let conn = Connection::open_with_flags(db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut stmt = conn.prepare("SELECT fname,usn,csum FROM media")?;
+    let rows = stmt.query_map([], |r| {
+        Ok(MediaRow {
+            fname: r.get(0)?,
+            usn: r.get(1)?,
+            csum: r.get(2)?,
+            size: 0,
+        })
+    })?;
+    let mut rows = rows.collect::<Result<Vec<_>, _>>()?;
+    for row in rows.iter_mut().filter(|r| r.csum.is_some()) {
+        row.size = fs::metadata(files.join(&row.fname)).map_or(0, |m| m.len());
+    }
+    Ok(rows)
+}
+
+/// check the copied collection,noting its problems
+fn validate_collection(folder: &Path, report: &mut UserMigration) -> Result<(), ApplicationError> {
+    let check = check_collection(folder, false)?;
+    report.problems = check.problems;
+    if check.integrity != ["ok"] {
+        report.problems.extend(check.integrity);
+    }
+    report.notes = check
+        .counts
+        .iter()
+        .find(|(table, _)| *table == "notes")
+        .map_or(0, |(_, n)| *n);
+    Ok(())
+}
+
+/// Copy the collection and media of a user of the python server to `folder`.
+fn migrate_python_user(
+    src: &Path,
+    folder: &Path,
+    report: &mut UserMigration,
+) -> Result<(), ApplicationError> {
+    let collection = src.join(COLLECTION_FILE);
+    if collection.exists() {
+        copy_database(&collection, &folder.join(COLLECTION_FILE))?;
+        validate_collection(folder, report)?;
+    }
+
+    // let anki create the media folder and database in the format it expects
+    drop(ServerMediaManager::new(folder)?);
+    let src_media = src.join(PYTHON_MEDIA_FOLDER);
+    let dest_media = media_folder(folder);
+    let src_db = src.join(PYTHON_MEDIA_DB);
+    if !src_db.exists() {
+        return Ok(());
+    }
+    let rows = python_media_rows(&src_db, &src_media)?;
+    for row in rows.iter().filter(|r| r.csum.is_some()) {
+        let from = src_media.join(&row.fname);
+        if from.is_file() {
+            fs::copy(&from, dest_media.join(&row.fname))?;
+            report.media_files += 1;
+        } else {
+            report.missing_media.push(row.fname.clone());
+        }
+    }
+    MediaDb::open(folder)?.import(&rows)?;
+    Ok(())
+}
+
+/// Import `users`,(name,password hash),whose data `copy` puts into their new folder.
+///
+/// Users already known to `auth_db` are skipped.A user is only added to `auth_db`
+/// once their data is copied,a failed user leaves nothing behind.
+fn import_users(
+    users: Vec<(String, Option<String>)>,
+    auth_db: &str,
+    data_root: &Path,
+    source: &str,
+    copy: impl Fn(&str, &Path, &mut UserMigration) -> Result<(), ApplicationError>,
+) -> Result<MigrationReport, ApplicationError> {
+    let mut report = MigrationReport::default();
+    for (name, hash) in users {
+        let mut user = UserMigration {
+            name: name.clone(),
+            ..Default::default()
+        };
+        let folder = data_root.join(&name);
+        if user_exists(&name, auth_db)? {
+            user.error = Some("already exists".to_string());
+        } else if folder.exists() && fs::read_dir(&folder)?.next().is_some() {
+            user.error = Some(format!("{} is not empty", folder.display()));
+        } else if let Some(hash) = hash {
+            fs::create_dir_all(&folder)?;
+            let result = copy(&name, &folder, &mut user).and_then(|_| {
+                Connection::open(auth_db)?.execute(
+                    "INSERT INTO auth (username,hash) VALUES (?, ?)",
+                    [&name, &hash],
+                )?;
+                Ok(())
+            });
+            match result {
+                Ok(()) => record_or_log(
+                    auth_db,
+                    &AuditEntry::event(&name, "admin", "ok")
+                        .detail(format!("migrated from the {source} server")),
+                ),
+                Err(e) => {
+                    user.error = Some(e.to_string());
+                    let _ = fs::remove_dir_all(&folder);
+                }
+            }
+        } else {
+            user.error = Some("no password given".to_string());
+        }
+        report.users.push(user);
+    }
+    Ok(report)
+}
+
+/// Import the users of the python anki-sync-server whose data root is `src`,
+/// keeping their password hashes and the usn of their media so that clients sync on normally.
+pub fn from_python(
+    src: &Path,
+    auth_db: &str,
+    data_root: &Path,
+) -> Result<MigrationReport, ApplicationError> {
+    let src_auth = src.join("auth.db");
+    if !src_auth.is_file() {
+        return Err(ApplicationError::NotFound(format!(
+            "no auth.db in {}",
+            src.display()
+        )));
+    }
+    let users = read_users(&src_auth)?
+        .into_iter()
+        .map(|(name, hash)| (name, Some(hash)))
+        .collect();
+    import_users(users, auth_db, data_root, "python", |name, folder, user| {
+        migrate_python_user(&src.join("collections").join(name), folder, user)
+    })
+}
+
+/// `SYNC_USER1=alice:secret` style credentials of the official server,
+/// from the lines of an env file or the environment.
+///
+/// Hashed passwords,used when `PASSWORDS_HASHED` is set,can not be converted and are left out.
+pub fn official_credentials(
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> HashMap<String, String> {
+    let vars: Vec<_> = vars.into_iter().collect();
+    let hashed = vars
+        .iter()
+        .any(|(k, v)| k == "PASSWORDS_HASHED" && !v.is_empty() && v != "0");
+    if hashed {
+        return HashMap::new();
+    }
+    vars.into_iter()
+        .filter(|(k, _)| {
+            k.strip_prefix("SYNC_USER")
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        })
+        .filter_map(|(_, v)| {
+            let (user, pass) = v.split_once(':')?;
+            Some((user.to_string(), pass.to_string()))
+        })
+        .collect()
+}
+
+/// parse `KEY=value` lines,ignoring comments and an `export ` prefix and unquoting values
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, ApplicationError> {
+    let text = fs::read_to_string(path)?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| {
+            let (k, v) = l.strip_prefix("export ").unwrap_or(l).split_once('=')?;
+            let v = v.trim();
+            let v = v
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .or_else(|| v.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                .unwrap_or(v);
+            Some((k.trim().to_string(), v.to_string()))
+        })
+        .collect())
+}
+
+/// Copy the folder of a user of the official server,which has the same layout as ours.
+fn migrate_official_user(
+    src: &Path,
+    folder: &Path,
+    report: &mut UserMigration,
+) -> Result<(), ApplicationError> {
+    let collection = src.join(COLLECTION_FILE);
+    if collection.exists() {
+        copy_database(&collection, &folder.join(COLLECTION_FILE))?;
+        validate_collection(folder, report)?;
+    }
+    let src_db = src.join(MEDIA_DB);
+    if src_db.exists() {
+        copy_database(&src_db, &folder.join(MEDIA_DB))?;
+    }
+    drop(ServerMediaManager::new(folder)?);
+    let src_media = media_folder(src);
+    if src_media.is_dir() {
+        let dest_media = media_folder(folder);
+        for entry in fs::read_dir(&src_media)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::copy(entry.path(), dest_media.join(entry.file_name()))?;
+                report.media_files += 1;
+            }
+        }
+    }
+    report.missing_media = check_media(folder)?.missing;
+    Ok(())
+}
+
+/// Import the users of the official sync server whose `SYNC_BASE` is `src`.
+///
+/// As the official server only keeps plain passwords in its environment,each user takes the password
+/// of `credentials`,or of `prompt` when missing.Collections and media databases are copied as they are,
+/// so that clients go on with normal syncs.
+pub fn from_official(
+    src: &Path,
+    auth_db: &str,
+    data_root: &Path,
+    credentials: &HashMap<String, String>,
+    prompt: impl Fn(&str) -> Option<String>,
+) -> Result<MigrationReport, ApplicationError> {
+    let mut names = vec![];
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.join(COLLECTION_FILE).exists() || path.join(MEDIA_DB).exists() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    if names.is_empty() {
+        return Err(ApplicationError::NotFound(format!(
+            "no user folder in {}",
+            src.display()
+        )));
+    }
+    names.sort();
+    let mut users = vec![];
+    for name in names {
+        // existing users are skipped,do not ask for their password
+        let password = if user_exists(&name, auth_db)? {
+            None
+        } else {
+            credentials.get(&name).cloned().or_else(|| prompt(&name))
+        };
+        let hash = password
+            .filter(|p| !p.is_empty())
+            .map(|p| hash_password(&name, &p));
+        users.push((name, hash));
+    }
+    import_users(
+        users,
+        auth_db,
+        data_root,
+        "official",
+        |name, folder, user| migrate_official_user(&src.join(name), folder, user),
+    )
+}
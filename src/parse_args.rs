@@ -1,22 +1,52 @@
-use crate::config::Config;
+use crate::audit::{print_audit, record_or_log, AuditEntry};
+use crate::backup;
+use crate::check;
+use crate::config::{find_config_file, Config, ConfigOverride, StorageKind};
+use crate::deck;
+use crate::dedup;
 use crate::error::ApplicationError;
-use crate::user::user_manage;
+use crate::export::{self, ExportFormat};
+use crate::group;
+use crate::import;
+use crate::init;
+use crate::lock::DataLock;
+use crate::media;
+use crate::migrate;
+use crate::storage;
+use crate::user::{prompt_password, user_exists, user_manage, Role};
 use clap::Parser;
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 #[derive(Parser, Debug)]
 #[clap( version,about, long_about = None)]
 pub struct Arg {
-    ///Sets a custom config file,ie -c ankisyncd.toml
+    ///Sets a custom config file,ie -c ankisyncd.toml.
+    ///Otherwise $ANKISYNCD_CONFIG,the XDG config dir,/etc/ankisyncd and the binary's directory are searched
     #[clap(short, long, value_parser, value_name("file"))]
     pub(crate) config: Option<PathBuf>,
     /// Show the default configuration
     #[clap(short, long, action)]
     pub(crate) default: bool,
+    /// Override the listen address,same as --set listen.host=HOST
+    #[clap(long, value_parser, value_name("host"))]
+    pub(crate) host: Option<String>,
+    /// Override the listen port,same as --set listen.port=PORT
+    #[clap(long, value_parser, value_name("port"))]
+    pub(crate) port: Option<u16>,
+    /// Override the data directory,same as --set paths.root_dir=DIR
+    #[clap(long, value_parser, value_name("dir"))]
+    pub(crate) root_dir: Option<String>,
+    /// Override the log level,same as --set log.level=LEVEL
+    #[clap(long, value_parser, value_name("level"))]
+    pub(crate) log_level: Option<String>,
+    /// Override any config key,i.e. --set sync.shutdown_grace_period=60
+    #[clap(long = "set", value_parser, value_name("key=value"))]
+    pub(crate) set: Vec<String>,
     #[command(subcommand)]
-    pub(crate) cmd: Option<UserCommand>,
+    pub(crate) cmd: Option<Command>,
 }
 #[derive(clap::Subcommand, Debug)]
-pub enum UserCommand {
+pub enum Command {
     /// user management,interact with db CRUD actions
     User {
         /// create user account, i.e.ankisyncd user -a username password
@@ -28,22 +58,861 @@ pub enum UserCommand {
         /// change user's password, i.e.ankisyncd user -p username newpassword
         #[clap(short, long, value_parser,number_of_values(2),value_names(&["username", "password"]))]
         pass: Option<Vec<String>>,
-        /// list all usernames extracted from db ,i.e.ankisyncd user  -l
+        /// list all usernames with their role,i.e.ankisyncd user  -l
         #[clap(short, long, action)]
         list: bool,
+        /// set the role of a user,user or admin,i.e.ankisyncd user --role alice admin.
+        /// admins may use the /admin api with their credentials
+        #[clap(long, value_parser,number_of_values(2),value_names(&["username", "role"]))]
+        role: Option<Vec<String>>,
+    },
+    /// query the audit log of syncs and account changes,i.e.ankisyncd audit --user alice --since 7d
+    Audit {
+        /// only show entries of this user
+        #[clap(short, long, value_parser, value_name("username"))]
+        user: Option<String>,
+        /// only show entries newer than this,such as 30m,12h,7d or 2w
+        #[clap(short, long, value_parser, value_name("duration"))]
+        since: Option<String>,
+    },
+    /// list or restore the snapshots of a user's collection,i.e.ankisyncd backup --list --user alice,
+    /// or archive all the data,i.e.ankisyncd backup --all --out archive.tar.zst
+    Backup {
+        /// user whose snapshots are listed or restored
+        #[clap(
+            short,
+            long,
+            value_parser,
+            value_name("username"),
+            required_unless_present("all")
+        )]
+        user: Option<String>,
+        /// list the snapshots,newest first,the default
+        #[clap(short, long, action, conflicts_with("restore"))]
+        list: bool,
+        /// replace the collection with this snapshot,the server must be stopped
+        #[clap(short, long, value_parser, value_name("id"))]
+        restore: Option<String>,
+        /// archive everything in root_dir:auth.db,the collections,media databases and media files
+        /// of every user,the shared decks and the media store,while the server runs or not
+        #[clap(long, action, requires("out"), conflicts_with_all(["user", "restore", "list"]))]
+        all: bool,
+        /// archive to write,compressed with zstd
+        #[clap(short, long, value_parser, value_name("file"))]
+        out: Option<PathBuf>,
+    },
+    /// check the integrity of a user's collection,i.e.ankisyncd check --user alice
+    Check {
+        /// user whose collection is checked
+        #[clap(short, long, value_parser, value_name("username"))]
+        user: String,
+        /// write the fixes to the collection after taking a snapshot,the server must be stopped
+        #[clap(long, action)]
+        repair: bool,
+    },
+    /// check or clean up the media of a user
+    Media {
+        #[command(subcommand)]
+        cmd: MediaCommand,
+    },
+    /// import the users and data of another sync server,
+    /// i.e.ankisyncd migrate --from-python /srv/anki-sync-server
+    #[command(group(clap::ArgGroup::new("source").required(true)))]
+    Migrate {
+        /// data root of the python anki-sync-server,holding auth.db and collections/
+        #[clap(long, value_parser, value_name("dir"), group("source"))]
+        from_python: Option<PathBuf>,
+        /// SYNC_BASE of the official anki sync server,holding a folder per user
+        #[clap(long, value_parser, value_name("dir"), group("source"))]
+        from_official: Option<PathBuf>,
+        /// env file with the SYNC_USER1=user:password lines of the official server,
+        /// the environment is used otherwise and missing passwords are asked for
+        #[clap(long, value_parser, value_name("file"), requires("from_official"))]
+        env_file: Option<PathBuf>,
+    },
+    /// export the collection of a user with its media,i.e.ankisyncd export --user alice --format apkg --out alice.apkg
+    Export {
+        /// user whose collection is exported
+        #[clap(short, long, value_parser, value_name("username"))]
+        user: String,
+        /// colpkg for the whole collection,apkg to merge notes into another collection
+        #[clap(short, long, value_enum, default_value_t = ExportFormat::Colpkg)]
+        format: ExportFormat,
+        /// only export this deck and its subdecks,needs apkg
+        #[clap(long, value_parser, value_name("name"))]
+        deck: Option<String>,
+        /// file to write
+        #[clap(short, long, value_parser, value_name("file"))]
+        out: PathBuf,
+    },
+    /// import an apkg into the collection of a user,or replace it with a colpkg,
+    /// i.e.ankisyncd import --user alice deck.apkg
+    Import {
+        /// user whose collection receives the package
+        #[clap(short, long, value_parser, value_name("username"))]
+        user: String,
+        /// apkg or colpkg to import
+        #[clap(value_parser, value_name("file"))]
+        file: PathBuf,
+    },
+    /// manage groups of users,i.e.ankisyncd group add biology alice bob
+    Group {
+        #[command(subcommand)]
+        cmd: GroupCommand,
+    },
+    /// send a deck maintained by an admin to a group of users,
+    /// i.e.ankisyncd deck publish --group biology --name "Biology 101" biology.apkg
+    Deck {
+        #[command(subcommand)]
+        cmd: DeckCommand,
+    },
+    /// write a commented default config file and create the data directory,
+    /// i.e.ankisyncd --root-dir /srv/ankisyncd init
+    Init {
+        /// replace an existing config file
+        #[clap(long, action)]
+        force: bool,
+    },
+    /// inspect the configuration
+    Config {
+        /// print the configuration after applying the file,env vars and command-line flags,
+        /// with secrets hidden.The default when --check is not given
+        #[clap(long, action)]
+        show_effective: bool,
+        /// validate the configuration,exit with an error if it has problems
+        #[clap(long, action)]
+        check: bool,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum MediaCommand {
+    /// compare the media folder with the media database,i.e.ankisyncd media check --user alice
+    Check {
+        /// user whose media is checked
+        #[clap(short, long, value_parser, value_name("username"))]
+        user: String,
+        /// reconcile the database with the folder,bumping the usn of every fixed file
+        /// so that clients sync it again.The server must be stopped
+        #[clap(long, action)]
+        fix: bool,
+    },
+    /// delete the media no note refers to,i.e.ankisyncd media gc --user alice --dry-run
+    Gc {
+        /// user whose media is cleaned up
+        #[clap(short, long, value_parser, value_name("username"))]
+        user: String,
+        /// only list the unused files and their size
+        #[clap(long, action)]
+        dry_run: bool,
+    },
+    /// convert existing media to the deduplicated store enabled by media.dedup,
+    /// i.e.ankisyncd media dedup
+    Dedup {
+        /// only convert the media of this user
+        #[clap(short, long, value_parser, value_name("username"))]
+        user: Option<String>,
     },
 }
 
-/// Get config from path (if specified) or default value,
+#[derive(clap::Subcommand, Debug)]
+pub enum GroupCommand {
+    /// create a group,i.e.ankisyncd group create biology
+    Create {
+        #[clap(value_parser, value_name("group"))]
+        name: String,
+    },
+    /// delete a group,its members are kept
+    Delete {
+        #[clap(value_parser, value_name("group"))]
+        name: String,
+    },
+    /// add users to a group,i.e.ankisyncd group add biology alice bob
+    Add {
+        #[clap(value_parser, value_name("group"))]
+        name: String,
+        #[clap(value_parser, value_name("username"), required(true))]
+        users: Vec<String>,
+        /// make them admins of the group,who may publish its shared decks
+        #[clap(long, action)]
+        admin: bool,
+    },
+    /// remove users from a group
+    Remove {
+        #[clap(value_parser, value_name("group"))]
+        name: String,
+        #[clap(value_parser, value_name("username"), required(true))]
+        users: Vec<String>,
+    },
+    /// list the groups,or the members of a group
+    List {
+        #[clap(value_parser, value_name("group"))]
+        name: Option<String>,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum DeckCommand {
+    /// publish a new revision of a shared deck and merge it into the collections of its group,
+    /// i.e.ankisyncd deck publish --group biology --name "Biology 101" biology.apkg
+    Publish {
+        /// name of the shared deck,the file name without extension by default
+        #[clap(short, long, value_parser, value_name("name"))]
+        name: Option<String>,
+        /// group receiving the deck,needed the first time
+        #[clap(short, long, value_parser, value_name("group"))]
+        group: Option<String>,
+        /// only store the revision,merge it later with `deck apply` while the server is stopped
+        #[clap(long, action)]
+        no_apply: bool,
+        /// apkg of the deck,exported without scheduling
+        #[clap(value_parser, value_name("file"))]
+        file: PathBuf,
+    },
+    /// merge the latest revisions into the collections missing them,i.e. of new members,
+    /// the server must be stopped
+    Apply {
+        /// only merge this shared deck
+        #[clap(short, long, value_parser, value_name("name"))]
+        deck: Option<String>,
+        /// only merge into the collection of this user
+        #[clap(short, long, value_parser, value_name("username"))]
+        user: Option<String>,
+    },
+    /// list the shared decks and how many members have their latest revision
+    List,
+    /// stop sending a shared deck,the notes already merged stay in the collections
+    Remove {
+        #[clap(value_parser, value_name("name"))]
+        name: String,
+    },
+}
+
+/// settings given as command-line flags
+pub fn cli_overrides(arg: &Arg) -> Result<Vec<ConfigOverride>, ApplicationError> {
+    let mut overrides = vec![];
+    if let Some(host) = &arg.host {
+        overrides.push(ConfigOverride::new("listen.host", host));
+    }
+    if let Some(port) = arg.port {
+        overrides.push(ConfigOverride::new("listen.port", port));
+    }
+    if let Some(dir) = &arg.root_dir {
+        overrides.push(ConfigOverride::new("paths.root_dir", dir));
+    }
+    if let Some(level) = &arg.log_level {
+        overrides.push(ConfigOverride::new("log.level", level));
+    }
+    for s in &arg.set {
+        overrides.push(ConfigOverride::parse(s)?);
+    }
+    Ok(overrides)
+}
+
+/// Get config from path (if specified),a standard location or default value,
+/// then apply env vars and command-line flags
 pub fn config_from_arguments(arg: &Arg) -> Result<Config, ApplicationError> {
-    if let Some(p) = arg.config.as_ref() {
-        return Config::from_file(p);
+    let path = arg.config.clone().or_else(find_config_file);
+    Config::load(path.as_deref(), &cli_overrides(arg)?)
+}
+
+fn backup_command(
+    conf: &Config,
+    user: &str,
+    restore: Option<&str>,
+) -> Result<(), ApplicationError> {
+    let auth_path = conf.auth_db_path();
+    if !user_exists(user, &auth_path)? {
+        return Err(ApplicationError::NotFound(format!("no user {user}")));
+    }
+    let folder = Path::new(&conf.data_root_path()).join(user);
+    if let Some(id) = restore {
+        let _lock = DataLock::for_offline(conf.root_dir(), "restore a snapshot")?;
+        let snapshot = backup::restore_snapshot(&folder, id)?;
+        record_or_log(
+            &auth_path,
+            &AuditEntry::event(user, "admin", "ok").detail(format!("restored snapshot {id}")),
+        );
+        println!(
+            "restored {} from {},clients will be asked for a full sync",
+            user,
+            snapshot
+                .created
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+        );
+    } else {
+        println!("id\tcreated\treason\tsize");
+        for s in backup::list_snapshots(&folder)? {
+            println!(
+                "{}\t{}\t{}\t{}",
+                s.id,
+                s.created
+                    .with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M:%S"),
+                s.reason,
+                s.size
+            );
+        }
+    }
+    Ok(())
+}
+
+fn check_command(conf: &Config, user: &str, repair: bool) -> Result<(), ApplicationError> {
+    let auth_path = conf.auth_db_path();
+    if !user_exists(user, &auth_path)? {
+        return Err(ApplicationError::NotFound(format!("no user {user}")));
+    }
+    let folder = Path::new(&conf.data_root_path()).join(user);
+    let _lock = if repair {
+        Some(DataLock::for_offline(
+            conf.root_dir(),
+            "repair a collection",
+        )?)
+    } else {
+        None
+    };
+    let report = check::check_collection(&folder, repair)?;
+    println!("integrity check: {}", report.integrity.join("; "));
+    for (table, count) in &report.counts {
+        println!("{table}: {count}");
+    }
+    if report.problems.is_empty() {
+        println!("no problems found");
+    } else if repair {
+        for p in &report.problems {
+            println!("fixed: {p}");
+        }
+        if let Some(id) = &report.snapshot {
+            println!("the collection before the repair is kept as snapshot {id}");
+        }
+        println!("clients will be asked for a full sync if the schema changed");
+        record_or_log(
+            &auth_path,
+            &AuditEntry::event(user, "admin", "ok")
+                .detail(format!("repaired {} problem(s)", report.problems.len())),
+        );
+    } else {
+        for p in &report.problems {
+            println!("found: {p}");
+        }
+        println!("run again with --repair to fix them");
+    }
+    Ok(())
+}
+
+fn media_check(conf: &Config, user: &str, fix: bool) -> Result<(), ApplicationError> {
+    let auth_path = conf.auth_db_path();
+    let folder = Path::new(&conf.data_root_path()).join(user);
+    let _lock = if fix {
+        Some(DataLock::for_offline(conf.root_dir(), "fix media")?)
+    } else {
+        None
+    };
+    let report = media::check_media(&folder)?;
+    for name in &report.missing {
+        println!("missing file: {name}");
+    }
+    for name in &report.untracked {
+        println!("file not in the database: {name}");
+    }
+    for name in &report.mismatched {
+        println!("checksum mismatch: {name}");
+    }
+    println!(
+        "{} file(s) checked,{} missing,{} not in the database,{} mismatched",
+        report.checked,
+        report.missing.len(),
+        report.untracked.len(),
+        report.mismatched.len()
+    );
+    if report.is_clean() {
+        return Ok(());
+    }
+    if fix {
+        media::fix_media(&folder, &report)?;
+        record_or_log(
+            &auth_path,
+            &AuditEntry::event(user, "admin", "ok").detail("media database reconciled"),
+        );
+        println!("fixed,clients will pick up the changes on their next sync");
+        if !report.missing.is_empty() {
+            println!("missing files were recorded as deleted,clients will delete their copies too");
+        }
+    } else {
+        println!("run again with --fix to reconcile them");
+    }
+    Ok(())
+}
+
+fn media_gc(conf: &Config, user: &str, dry_run: bool) -> Result<(), ApplicationError> {
+    let auth_path = conf.auth_db_path();
+    let folder = Path::new(&conf.data_root_path()).join(user);
+    let _lock = if dry_run {
+        None
+    } else {
+        Some(DataLock::for_offline(conf.root_dir(), "delete media")?)
+    };
+    let report = media::unused_media(&folder)?;
+    for (name, size) in &report.unused {
+        println!("{size:>12}  {name}");
+    }
+    println!(
+        "{} unused file(s),{} bytes",
+        report.unused.len(),
+        report.total_size()
+    );
+    if report.unused.is_empty() {
+        return Ok(());
+    }
+    if dry_run {
+        println!("run again without --dry-run to delete them");
+        return Ok(());
+    }
+    media::remove_unused(&folder, &report)?;
+    record_or_log(
+        &auth_path,
+        &AuditEntry::event(user, "admin", "ok").detail(format!(
+            "deleted {} unused media file(s)",
+            report.unused.len()
+        )),
+    );
+    println!("deleted,clients will delete their copies on their next sync");
+    if let Some(store) = conf.media_store() {
+        dedup::collect_garbage(&store)?;
+    }
+    Ok(())
+}
+
+fn media_dedup(conf: &Config, user: Option<&str>) -> Result<(), ApplicationError> {
+    let Some(store) = conf.media_store() else {
+        return Err(ApplicationError::ParseConfig(
+            "set dedup = true in the [media] section first,so that the server keeps new media deduplicated"
+                .to_string(),
+        ));
+    };
+    // the server would sync media while their files are replaced by links
+    let _lock = DataLock::for_offline(conf.root_dir(), "deduplicate media")?;
+    let data_root = PathBuf::from(conf.data_root_path());
+    let users = match user {
+        Some(u) => vec![u.to_string()],
+        None => {
+            let mut users = vec![];
+            for entry in std::fs::read_dir(&data_root)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    users.push(entry.file_name().to_string_lossy().to_string());
+                }
+            }
+            users.sort();
+            users
+        }
+    };
+    let mut saved = 0;
+    for user in &users {
+        let summary = dedup::dedup_user(&store, &data_root.join(user))?;
+        println!(
+            "{user}: {} file(s),{} bytes saved",
+            summary.files, summary.saved
+        );
+        saved += summary.saved;
+    }
+    let (removed, size) = dedup::collect_garbage(&store)?;
+    println!("{saved} bytes saved,{removed} unused blob(s) of {size} bytes removed");
+    Ok(())
+}
+
+fn media_command(conf: &Config, cmd: &MediaCommand) -> Result<(), ApplicationError> {
+    let user = match cmd {
+        MediaCommand::Check { user, .. } | MediaCommand::Gc { user, .. } => Some(user),
+        MediaCommand::Dedup { user } => user.as_ref(),
+    };
+    if let Some(user) = user {
+        if !user_exists(user, &conf.auth_db_path())? {
+            return Err(ApplicationError::NotFound(format!("no user {user}")));
+        }
+    }
+    let remote = conf.media_config().storage != StorageKind::Local;
+    if remote && !matches!(cmd, MediaCommand::Dedup { .. }) {
+        return Err(ApplicationError::ParseConfig(
+            "media check and gc only work with local media storage".to_string(),
+        ));
+    }
+    match cmd {
+        MediaCommand::Check { user, fix } => media_check(conf, user, *fix),
+        MediaCommand::Gc { user, dry_run } => media_gc(conf, user, *dry_run),
+        MediaCommand::Dedup { user } => media_dedup(conf, user.as_deref()),
+    }
+}
+
+fn import_command(conf: &Config, user: &str, file: &Path) -> Result<(), ApplicationError> {
+    let auth_path = conf.auth_db_path();
+    if !user_exists(user, &auth_path)? {
+        return Err(ApplicationError::NotFound(format!("no user {user}")));
+    }
+    if conf.media_config().storage != StorageKind::Local {
+        return Err(ApplicationError::ParseConfig(
+            "import only works with local media storage".to_string(),
+        ));
+    }
+    let _lock = DataLock::for_offline(conf.root_dir(), "import a package")?;
+    let folder = Path::new(&conf.data_root_path()).join(user);
+    let report = import::import_package(&folder, file, conf.media_store().as_deref())?;
+    let name = file.file_name().unwrap_or_default().to_string_lossy();
+    record_or_log(
+        &auth_path,
+        &AuditEntry::event(user, "admin", "ok").detail(format!("imported {name}")),
+    );
+    if report.replaced {
+        println!(
+            "replaced the collection of {user} with {name},clients will be asked for a full sync"
+        );
+    } else {
+        println!(
+            "imported {name} into the collection of {user}: {} new note(s),{} updated,{} already present",
+            report.new_notes, report.updated_notes, report.duplicate_notes
+        );
+        println!("clients will pick up the new content on their next sync");
+    }
+    println!("{} media file(s) added", report.media_files.len());
+    if let Some(id) = &report.snapshot {
+        println!("the collection before the import is kept as snapshot {id}");
+    }
+    Ok(())
+}
+
+fn group_command(auth_path: &str, cmd: &GroupCommand) -> Result<(), ApplicationError> {
+    match cmd {
+        GroupCommand::Create { name } => {
+            group::create_group(name, auth_path)?;
+            println!("created group {name}");
+        }
+        GroupCommand::Delete { name } => {
+            let decks = deck::decks_of_group(name, auth_path)?;
+            if !decks.is_empty() {
+                return Err(ApplicationError::Conflict(format!(
+                    "{name} receives the shared deck(s) {},remove them first",
+                    decks.join(",")
+                )));
+            }
+            group::delete_group(name, auth_path)?;
+            println!("deleted group {name}");
+        }
+        GroupCommand::Add { name, users, admin } => {
+            let role = if *admin { Role::Admin } else { Role::User };
+            group::add_members(name, users, role, auth_path)?;
+            for user in users {
+                record_or_log(
+                    auth_path,
+                    &AuditEntry::event(user, "admin", "ok")
+                        .detail(format!("added to group {name} as {}", role.as_str())),
+                );
+            }
+            println!("added {} user(s) to {name}", users.len());
+            if !deck::decks_of_group(name, auth_path)?.is_empty() {
+                println!("run `ankisyncd deck apply` to send them the shared decks of {name}");
+            }
+        }
+        GroupCommand::Remove { name, users } => {
+            group::remove_members(name, users, auth_path)?;
+            for user in users {
+                record_or_log(
+                    auth_path,
+                    &AuditEntry::event(user, "admin", "ok")
+                        .detail(format!("removed from group {name}")),
+                );
+            }
+            println!("removed {} user(s) from {name}", users.len());
+        }
+        GroupCommand::List { name: Some(name) } => {
+            println!("user\trole");
+            for (user, role) in group::member_roles(name, auth_path)? {
+                println!("{user}\t{}", role.as_str());
+            }
+        }
+        GroupCommand::List { name: None } => {
+            println!("group\tmembers");
+            for (name, count) in group::list_groups(auth_path)? {
+                println!("{name}\t{count}");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// the lock needed to merge shared decks into the collections
+fn deck_merge_lock(conf: &Config) -> Result<DataLock, ApplicationError> {
+    if conf.media_config().storage != StorageKind::Local {
+        return Err(ApplicationError::ParseConfig(
+            "shared decks can only be merged with local media storage".to_string(),
+        ));
+    }
+    // the admin api only stores revisions,so do not point to it like for_offline
+    DataLock::try_acquire(conf.root_dir())?.ok_or_else(|| {
+        ApplicationError::Conflict(
+            "the server is running,stop it to merge shared decks or publish with --no-apply \
+             and run deck apply once it is stopped"
+                .to_string(),
+        )
+    })
+}
+
+/// merge the pending revisions of shared decks,printing the outcome for each member
+fn deck_apply(
+    conf: &Config,
+    deck_name: Option<&str>,
+    user: Option<&str>,
+) -> Result<(), ApplicationError> {
+    let auth_path = conf.auth_db_path();
+    let data_root = PathBuf::from(conf.data_root_path());
+    let store = conf.media_store();
+    let updates = deck::apply_pending(
+        conf.root_dir(),
+        &auth_path,
+        &data_root,
+        store.as_deref(),
+        deck_name,
+        user,
+    )?;
+    let mut failed = 0;
+    for update in &updates {
+        match &update.result {
+            Ok(report) => {
+                record_or_log(
+                    &auth_path,
+                    &AuditEntry::event(&update.user, "admin", "ok").detail(format!(
+                        "merged revision {} of shared deck {}",
+                        update.revision, update.deck
+                    )),
+                );
+                println!(
+                    "{}: {} revision {},{} new note(s),{} updated,{} media file(s)",
+                    update.user,
+                    update.deck,
+                    update.revision,
+                    report.new_notes,
+                    report.updated_notes,
+                    report.media_files.len()
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                println!(
+                    "{}: {} revision {} failed: {e}",
+                    update.user, update.deck, update.revision
+                );
+            }
+        }
+    }
+    println!(
+        "{} collection(s) updated,{failed} failed,clients will pick up the changes on their next sync",
+        updates.len() - failed
+    );
+    if failed > 0 {
+        println!("failed members are retried by the next `ankisyncd deck apply`");
+    }
+    Ok(())
+}
+
+fn deck_command(conf: &Config, cmd: &DeckCommand) -> Result<(), ApplicationError> {
+    let auth_path = conf.auth_db_path();
+    match cmd {
+        DeckCommand::Publish {
+            name,
+            group,
+            no_apply,
+            file,
+        } => {
+            let name = match name {
+                Some(n) => n.clone(),
+                None => file
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+            };
+            let _lock = if *no_apply {
+                None
+            } else {
+                Some(deck_merge_lock(conf)?)
+            };
+            let published =
+                deck::publish(conf.root_dir(), &auth_path, &name, group.as_deref(), file)?;
+            println!(
+                "published revision {} of {name} for group {}",
+                published.revision, published.group
+            );
+            if *no_apply {
+                println!("run `ankisyncd deck apply` with the server stopped to merge it");
+            } else {
+                deck_apply(conf, Some(&name), None)?;
+            }
+        }
+        DeckCommand::Apply { deck, user } => {
+            let _lock = deck_merge_lock(conf)?;
+            deck_apply(conf, deck.as_deref(), user.as_deref())?
+        }
+        DeckCommand::List => {
+            println!("name\tgroup\trevision\tpublished\tup to date");
+            for d in deck::list_decks(&auth_path)? {
+                let (current, members) = deck::deck_status(&d, &auth_path)?;
+                println!(
+                    "{}\t{}\t{}\t{}\t{current}/{members}",
+                    d.name, d.group, d.revision, d.published
+                );
+            }
+        }
+        DeckCommand::Remove { name } => {
+            deck::remove_deck(conf.root_dir(), &auth_path, name)?;
+            println!("removed shared deck {name}");
+        }
+    }
+    Ok(())
+}
+
+fn migrate_command(
+    conf: &Config,
+    from_python: Option<&Path>,
+    from_official: Option<&Path>,
+    env_file: Option<&Path>,
+) -> Result<(), ApplicationError> {
+    let _lock = DataLock::for_offline(conf.root_dir(), "migrate")?;
+    let data_root = PathBuf::from(conf.data_root_path());
+    let auth_path = conf.auth_db_path();
+    let report = match (from_python, from_official) {
+        (Some(src), _) => migrate::from_python(src, &auth_path, &data_root)?,
+        (None, Some(src)) => {
+            let vars = match env_file {
+                Some(f) => migrate::read_env_file(f)?,
+                None => std::env::vars().collect(),
+            };
+            let credentials = migrate::official_credentials(vars);
+            let interactive = std::io::stdin().is_terminal();
+            migrate::from_official(src, &auth_path, &data_root, &credentials, |name| {
+                if !interactive {
+                    return None;
+                }
+                prompt_password(&format!("password of {name} (empty to skip): ")).ok()
+            })?
+        }
+        (None, None) => unreachable!("clap requires a source"),
+    };
+    for user in &report.users {
+        if let Some(e) = &user.error {
+            println!("{}: skipped,{e}", user.name);
+            continue;
+        }
+        println!(
+            "{}: migrated,{} note(s),{} media file(s)",
+            user.name, user.notes, user.media_files
+        );
+        for p in &user.problems {
+            println!("  collection: {p}");
+        }
+        for name in &user.missing_media {
+            println!("  missing media file: {name}");
+        }
+        if !user.problems.is_empty() {
+            println!("  run ankisyncd check --user {} --repair", user.name);
+        }
+        if !user.missing_media.is_empty() {
+            println!("  run ankisyncd media check --user {} --fix", user.name);
+        }
+    }
+    println!(
+        "{} of {} user(s) migrated",
+        report.migrated(),
+        report.users.len()
+    );
+    Ok(())
+}
+
+/// Run `ankisyncd init`,which writes the config file instead of reading it
+pub fn run_init(arg: &Arg, force: bool) -> Result<(), ApplicationError> {
+    init::init(arg.config.as_deref(), arg.root_dir.as_deref(), force)
+}
+
+/// Run a subcommand instead of the server
+pub fn run_command(cmd: &Command, conf: &Config) -> Result<(), ApplicationError> {
+    let auth_path = conf.auth_db_path();
+    match cmd {
+        Command::User { .. } => manage_user(cmd, &auth_path),
+        Command::Audit { user, since } => {
+            print_audit(&auth_path, user.as_deref(), since.as_deref())?
+        }
+        Command::Backup {
+            all: true,
+            out: Some(out),
+            ..
+        } => {
+            let summary = backup::archive_all(conf.root_dir(), out)?;
+            println!(
+                "wrote {}: {} user(s),{} database(s),{} other file(s)",
+                out.display(),
+                summary.users,
+                summary.databases,
+                summary.files
+            );
+        }
+        Command::Backup { user, restore, .. } => {
+            let user = user.as_deref().expect("required without --all");
+            backup_command(conf, user, restore.as_deref())?
+        }
+        Command::Check { user, repair } => check_command(conf, user, *repair)?,
+        Command::Media { cmd } => media_command(conf, cmd)?,
+        Command::Migrate {
+            from_python,
+            from_official,
+            env_file,
+        } => migrate_command(
+            conf,
+            from_python.as_deref(),
+            from_official.as_deref(),
+            env_file.as_deref(),
+        )?,
+        Command::Export {
+            user,
+            format,
+            deck,
+            out,
+        } => {
+            if !user_exists(user, &auth_path)? {
+                return Err(ApplicationError::NotFound(format!("no user {user}")));
+            }
+            let folder = Path::new(&conf.data_root_path()).join(user);
+            let storage = storage::from_config(conf.media_config());
+            export::export_collection(&folder, storage.as_ref(), *format, deck.as_deref(), out)?;
+            record_or_log(
+                &auth_path,
+                &AuditEntry::event(user, "export", "ok").detail(format.extension()),
+            );
+            println!("exported the collection of {user} to {}", out.display());
+        }
+        Command::Import { user, file } => import_command(conf, user, file)?,
+        Command::Group { cmd } => group_command(&auth_path, cmd)?,
+        Command::Deck { cmd } => deck_command(conf, cmd)?,
+        Command::Init { .. } => unreachable!("init runs before the config is loaded"),
+        Command::Config {
+            show_effective,
+            check,
+        } => {
+            if *show_effective || !*check {
+                if let Some(p) = conf.source() {
+                    println!("# loaded from {}", p.display());
+                }
+                println!("{}", conf.to_redacted_string()?);
+            }
+            if *check {
+                conf.validate()?;
+                println!("configuration is valid");
+            }
+        }
     }
-    Ok(Config::default())
+    Ok(())
 }
 
 /// Manage user
-pub fn manage_user(cmd: &UserCommand, auth_path: &str) {
+pub fn manage_user(cmd: &Command, auth_path: &str) {
     if let Err(e) = user_manage(cmd, auth_path) {
         panic!("Error managing users: {e}");
     };
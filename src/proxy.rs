@@ -0,0 +1,258 @@
+// resolve the client address of requests coming through a trusted reverse proxy
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderMap;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Proxies whose forwarding headers are honoured,from `trusted_proxies` in the config.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+    /// trust peers connected through the unix socket
+    unix: bool,
+}
+
+impl TrustedProxies {
+    /// parse a list of CIDRs,plain addresses are taken as a single host and
+    /// `unix` stands for clients of the unix socket
+    pub fn parse(list: &[String]) -> Result<Self, String> {
+        let mut trusted = TrustedProxies::default();
+        for s in list {
+            if s == "unix" {
+                trusted.unix = true;
+                continue;
+            }
+            let net = s
+                .parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("invalid trusted proxy address: {s}"))?;
+            trusted.nets.push(net);
+        }
+        Ok(trusted)
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    fn trusts_peer(&self, peer: Option<IpAddr>) -> bool {
+        match peer {
+            Some(ip) => self.contains(&ip),
+            None => self.unix,
+        }
+    }
+}
+
+/// address and scheme of the client as seen by the first proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr {
+    pub ip: Option<IpAddr>,
+    pub https: bool,
+}
+
+/// parse `1.2.3.4`,`1.2.3.4:80`,`2001:db8::1` or `[2001:db8::1]:80`
+fn parse_node(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    s.parse::<IpAddr>()
+        .ok()
+        .or_else(|| s.parse::<SocketAddr>().ok().map(|a| a.ip()))
+        .or_else(|| {
+            s.strip_prefix('[')
+                .and_then(|s| s.strip_suffix(']'))
+                .and_then(|s| s.parse().ok())
+        })
+}
+
+/// (for,proto) pairs of the RFC 7239 `Forwarded` header,nearest proxy last
+fn forwarded(headers: &HeaderMap) -> Vec<(Option<IpAddr>, Option<String>)> {
+    headers
+        .get_all("forwarded")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|element| {
+            let mut node = None;
+            let mut proto = None;
+            for pair in element.split(';') {
+                if let Some((k, v)) = pair.split_once('=') {
+                    match k.trim().to_ascii_lowercase().as_str() {
+                        "for" => node = parse_node(v),
+                        "proto" => proto = Some(v.trim().trim_matches('"').to_ascii_lowercase()),
+                        _ => {}
+                    }
+                }
+            }
+            (node, proto)
+        })
+        .collect()
+}
+
+/// addresses of the `X-Forwarded-For` header,nearest proxy last
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("x-forwarded-for")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Work out the client address.
+///
+/// Forwarding headers are only read when the peer is a trusted proxy.The chain is walked
+/// from the nearest hop and the first address that is not a trusted proxy is the client.
+pub fn client_addr(req: &ServiceRequest, trusted: &TrustedProxies) -> ClientAddr {
+    let peer = req.peer_addr().map(|a| a.ip());
+    let direct = ClientAddr {
+        ip: peer,
+        https: req.app_config().secure(),
+    };
+    if !trusted.trusts_peer(peer) {
+        return direct;
+    }
+    let headers = req.headers();
+    let (chain, proto) = {
+        let fwd = forwarded(headers);
+        if fwd.is_empty() {
+            let proto = headers
+                .get("x-forwarded-proto")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.split(',').next())
+                .map(|v| v.trim().to_ascii_lowercase());
+            (x_forwarded_for(headers), proto)
+        } else {
+            // the proto of the first hop is the one the client used
+            let proto = fwd.iter().find_map(|(_, p)| p.clone());
+            (fwd.into_iter().map(|(ip, _)| ip).collect(), proto)
+        }
+    };
+    if chain.is_empty() {
+        return direct;
+    }
+    let mut ip = peer;
+    for hop in chain.iter().rev() {
+        match hop {
+            Some(hop) => {
+                ip = Some(*hop);
+                if !trusted.contains(hop) {
+                    break;
+                }
+            }
+            // obfuscated or unknown node,do not look further
+            None => break,
+        }
+    }
+    ClientAddr {
+        ip,
+        https: proto.map_or(direct.https, |p| p == "https"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn trusted(list: &[&str]) -> TrustedProxies {
+        TrustedProxies::parse(&list.iter().map(|s| s.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    fn addr(peer: &str, headers: &[(&str, &str)], trusted: &TrustedProxies) -> ClientAddr {
+        let mut req = TestRequest::default().peer_addr(peer.parse().unwrap());
+        for &(name, value) in headers {
+            req = req.append_header((name, value));
+        }
+        client_addr(&req.to_srv_request(), trusted)
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn untrusted_peer_headers_are_ignored() {
+        let trusted = trusted(&["10.0.0.0/8"]);
+        let headers = [
+            ("x-forwarded-for", "1.2.3.4"),
+            ("x-forwarded-proto", "https"),
+            ("forwarded", "for=5.6.7.8;proto=https"),
+        ];
+        let client = addr("203.0.113.9:4000", &headers, &trusted);
+        assert_eq!(
+            client,
+            ClientAddr {
+                ip: ip("203.0.113.9"),
+                https: false
+            }
+        );
+    }
+
+    #[test]
+    fn trusted_hops_are_skipped_from_the_right() {
+        let trusted = trusted(&["10.0.0.0/8", "192.168.1.1"]);
+        // a spoofed address sent by the client stays left of the real one
+        let headers = [("x-forwarded-for", "6.6.6.6, 1.2.3.4, 192.168.1.1,10.0.0.7")];
+        let client = addr("10.0.0.1:4000", &headers, &trusted);
+        assert_eq!(client.ip, ip("1.2.3.4"));
+        // headers repeated by several proxies form one chain
+        let headers = [
+            ("x-forwarded-for", "6.6.6.6, 1.2.3.4"),
+            ("x-forwarded-for", "10.0.0.7"),
+            ("x-forwarded-proto", "https, http"),
+        ];
+        let client = addr("10.0.0.1:4000", &headers, &trusted);
+        assert_eq!(
+            client,
+            ClientAddr {
+                ip: ip("1.2.3.4"),
+                https: true
+            }
+        );
+        // only trusted proxies,the farthest one is the client
+        let headers = [("x-forwarded-for", "10.0.0.8, 10.0.0.7")];
+        assert_eq!(addr("10.0.0.1:4000", &headers, &trusted).ip, ip("10.0.0.8"));
+    }
+
+    #[test]
+    fn forwarded_header_quoting() {
+        let trusted = trusted(&["10.0.0.0/8"]);
+        let headers = [(
+            "forwarded",
+            r#"for="[2001:db8:cafe::17]:4711";proto=https, For=10.0.0.7"#,
+        )];
+        let client = addr("10.0.0.1:4000", &headers, &trusted);
+        assert_eq!(
+            client,
+            ClientAddr {
+                ip: ip("2001:db8:cafe::17"),
+                https: true
+            }
+        );
+        let headers = [("forwarded", r#"for="1.2.3.4:80";proto="HTTP""#)];
+        let client = addr("10.0.0.1:4000", &headers, &trusted);
+        assert_eq!(
+            client,
+            ClientAddr {
+                ip: ip("1.2.3.4"),
+                https: false
+            }
+        );
+        // Forwarded takes precedence over X-Forwarded-For
+        let headers = [("x-forwarded-for", "5.6.7.8"), ("forwarded", "for=1.2.3.4")];
+        assert_eq!(addr("10.0.0.1:4000", &headers, &trusted).ip, ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn malformed_entries_stop_the_walk() {
+        let trusted = trusted(&["10.0.0.0/8"]);
+        // an address left of an unparsable hop can not be trusted
+        let headers = [("x-forwarded-for", "1.2.3.4, garbage, 10.0.0.7")];
+        assert_eq!(addr("10.0.0.1:4000", &headers, &trusted).ip, ip("10.0.0.7"));
+        let headers = [("forwarded", "for=unknown")];
+        assert_eq!(addr("10.0.0.1:4000", &headers, &trusted).ip, ip("10.0.0.1"));
+        let headers = [("forwarded", "for=_hidden, for=1.2.3.4;proto")];
+        assert_eq!(addr("10.0.0.1:4000", &headers, &trusted).ip, ip("1.2.3.4"));
+        let headers = [("x-forwarded-for", "")];
+        assert_eq!(addr("10.0.0.1:4000", &headers, &trusted).ip, ip("10.0.0.1"));
+        assert!(TrustedProxies::parse(&["10.0.0.0/33".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+// reload ankisyncd.toml on SIGHUP or when the file changes
+use actix_web::web;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "tls")]
+use crate::app_config::CertResolver;
+use crate::config::{Config, ConfigBackup};
+use crate::error::ApplicationError;
+use crate::logging;
+use crate::proxy::TrustedProxies;
+#[cfg(feature = "account")]
+use crate::user::create_user_from_conf;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+/// how often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The settings that can change while the server runs.
+pub struct LiveConfig {
+    config: RwLock<Config>,
+    trusted_proxies: RwLock<TrustedProxies>,
+    #[cfg(feature = "tls")]
+    certs: Option<Arc<CertResolver>>,
+}
+
+impl LiveConfig {
+    pub fn new(config: &Config) -> Result<Self, ApplicationError> {
+        let trusted_proxies = TrustedProxies::parse(config.trusted_proxies())
+            .map_err(ApplicationError::ParseConfig)?;
+        Ok(LiveConfig {
+            config: RwLock::new(config.clone()),
+            trusted_proxies: RwLock::new(trusted_proxies),
+            #[cfg(feature = "tls")]
+            certs: None,
+        })
+    }
+
+    /// reload the certificate and key files along with the config
+    #[cfg(feature = "tls")]
+    pub fn with_certs(mut self, certs: Arc<CertResolver>) -> Self {
+        self.certs = Some(certs);
+        self
+    }
+
+    pub fn with_trusted_proxies<T>(&self, f: impl FnOnce(&TrustedProxies) -> T) -> T {
+        f(&self.trusted_proxies.read().expect("config lock"))
+    }
+
+    pub fn shutdown_grace_period(&self) -> Duration {
+        self.config
+            .read()
+            .expect("config lock")
+            .shutdown_grace_period()
+    }
+
+    pub fn backup_config(&self) -> ConfigBackup {
+        self.config
+            .read()
+            .expect("config lock")
+            .backup_config()
+            .clone()
+    }
+
+    pub fn admin_token(&self) -> Option<String> {
+        let config = self.config.read().expect("config lock");
+        config.admin_token().map(|t| t.to_string())
+    }
+
+    pub fn media_store(&self) -> Option<PathBuf> {
+        self.config.read().expect("config lock").media_store()
+    }
+
+    /// Read the config file again and apply what can be applied at runtime.
+    ///
+    /// Nothing is changed if the new config is invalid.
+    pub fn reload(&self) -> Result<(), ApplicationError> {
+        let current = self.config.read().expect("config lock").clone();
+        let Some(path) = current.source() else {
+            log::warn!("server was started without a config file,nothing to reload");
+            return Ok(());
+        };
+        log::info!("reloading config from {}", path.display());
+        let new = current.reread()?;
+        new.validate()?;
+        let level = new.log_level()?;
+        let trusted_proxies =
+            TrustedProxies::parse(new.trusted_proxies()).map_err(ApplicationError::ParseConfig)?;
+        #[cfg(feature = "tls")]
+        if let (Some(certs), Some(c)) = (&self.certs, new.encryption_config()) {
+            certs.reload(c)?;
+            log::info!("reloaded tls certificate {}", c.cert_file);
+        }
+        logging::set_level(level);
+        *self.trusted_proxies.write().expect("config lock") = trusted_proxies;
+        #[cfg(feature = "account")]
+        if let Some(account) = new.account.clone() {
+            create_user_from_conf(account, new.auth_db_path());
+        }
+        let mut config = self.config.write().expect("config lock");
+        for setting in config.restart_needed(&new) {
+            log::warn!("{setting} changed,restart the server to apply it");
+        }
+        *config = new;
+        Ok(())
+    }
+
+    fn reload_or_log(&self) {
+        if let Err(e) = self.reload() {
+            log::error!("config not reloaded: {e}");
+        }
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        let config = self.config.read().expect("config lock");
+        std::fs::metadata(config.source()?).ok()?.modified().ok()
+    }
+}
+
+/// Reload the config on SIGHUP,and when the modification time of the file changes.
+pub async fn watch(live: web::Data<LiveConfig>) {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        let live = live.clone();
+        match signal(SignalKind::hangup()) {
+            Ok(mut hup) => {
+                actix_web::rt::spawn(async move {
+                    while hup.recv().await.is_some() {
+                        live.reload_or_log();
+                    }
+                });
+            }
+            Err(e) => log::error!("unable to listen for SIGHUP: {e}"),
+        }
+    }
+    let mut last = live.modified();
+    let mut timer = actix_web::rt::time::interval(WATCH_INTERVAL);
+    loop {
+        timer.tick().await;
+        let modified = live.modified();
+        if modified != last {
+            last = modified;
+            live.reload_or_log();
+        }
+    }
+}
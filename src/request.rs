@@ -4,8 +4,10 @@
 // And middleware method reference to https://github.com/actix/examples/blob/db2edcaeb1fdf8c609e42f4e569122ef5d8ae613/middleware/middleware-ext-mut/src/add_msg.rs
 use actix_web::{
     dev::{self, Service, ServiceRequest, ServiceResponse, Transform},
-    Error, HttpMessage,
+    http::header::{HeaderName, HeaderValue},
+    web, Error, HttpMessage,
 };
+use anki::sync::http_server::SimpleServer;
 use anki::sync::request::multipart::decode_gzipped_data;
 use anki::sync::request::SyncRequest;
 use anki::sync::version::SyncVersion;
@@ -17,14 +19,24 @@ use anki::sync::{
 use async_std::io::WriteExt;
 use futures_util::{future::LocalBoxFuture, TryStreamExt};
 use std::net::IpAddr;
+use std::time::Instant;
 use std::{
     collections::HashMap,
     future::{ready, Ready},
+    path::PathBuf,
     rc::Rc,
+    sync::Arc,
 };
 
 use crate::{
+    audit::{changes_in_request, AuditLog},
     error::ApplicationError,
+    lock::is_sync_paused,
+    logging::{new_request_id, SyncEvent},
+    metrics::METRICS,
+    proxy::{client_addr, TrustedProxies},
+    reload::LiveConfig,
+    shutdown::{starts_session, Shutdown},
     user::{compute_hash, UserError},
 };
 /// Get the full field data as text.
@@ -116,6 +128,27 @@ pub(super) async fn from_header_and_stream<T>(
     }
 }
 
+/// name of the user the request belongs to,used for logging.
+///
+/// the login request carries the username itself,the others are looked up by host key.
+fn request_username(
+    req: &ServiceRequest,
+    method: &str,
+    sync_request: &SyncRequest<Vec<u8>>,
+) -> Option<String> {
+    if method == "hostKey" {
+        return serde_json::from_slice::<HostKeyRequest>(&sync_request.data)
+            .ok()
+            .map(|r| r.username);
+    }
+    let server = req.app_data::<web::Data<Arc<SimpleServer>>>()?;
+    let state = server.state.lock().ok()?;
+    state
+        .users
+        .get(&sync_request.sync_key)
+        .map(|u| u.name.clone())
+}
+
 #[derive(Clone)]
 pub struct SyncRequestW(pub SyncRequest<Vec<u8>>);
 // #[derive(Clone)]
@@ -147,19 +180,38 @@ where
     fn call(&self, mut req: ServiceRequest) -> Self::Future {
         let service = self.service.clone();
         Box::pin(async move {
+            let start = Instant::now();
+            let request_id = new_request_id();
+            // last path segment,such as `meta` for /sync/meta or `begin` for /msync/begin
+            let method = req
+                .path()
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            if starts_session(&method)
+                && req
+                    .app_data::<web::Data<Shutdown>>()
+                    .is_some_and(|s| s.is_draining())
+            {
+                return Err(actix_web::error::ErrorServiceUnavailable(
+                    "server is shutting down",
+                ));
+            }
             // let r:anki::sync::media::begin::SyncBeginQuery=serde_json::from_str( req.query_string()).unwrap();
             // let headers = req.headers();
             let pl = req.take_payload();
             // let (req,pl)=req.into_parts();
             let headers = req.headers();
-            let ip = req.peer_addr();
-            let ip: Option<std::net::IpAddr> = match ip {
-                Some(s) => Some(s.ip()),
-                None => {
-                    log::error!("unable to get ip");
-                    None
-                }
+            let client = match req.app_data::<web::Data<LiveConfig>>() {
+                Some(live) => live.with_trusted_proxies(|trusted| client_addr(&req, trusted)),
+                None => client_addr(&req, &TrustedProxies::default()),
             };
+            // connections on a unix socket have no peer address,anki needs one anyway
+            let ip = client
+                .ip
+                .unwrap_or(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED));
             // construct struct SyncHeader.
             let sync_header_value =
                 headers.get(&anki::sync::request::header_and_stream::SYNC_HEADER_NAME);
@@ -174,18 +226,73 @@ where
                             .unwrap();
                     // let pl = req.take_payload();
 
-                    from_header_and_stream::<Vec<u8>>(sync_header.unwrap(), pl, ip.unwrap()).await
+                    from_header_and_stream::<Vec<u8>>(sync_header.unwrap(), pl, ip).await
                 }
                 None => {
                     // let pl = req.take_payload();
                     // If SYNC_HEADER_NAME is absent,
                     let pl = actix_multipart::Multipart::new(headers, pl);
 
-                    from_multipart::<Vec<u8>>(ip.unwrap(), pl).await
+                    from_multipart::<Vec<u8>>(ip, pl).await
                 }
             };
+            METRICS.add_bytes_in(sync_request.data.len());
+            let username = request_username(&req, &method, &sync_request);
+            // an online backup of this user is being taken
+            if starts_session(&method) {
+                let folder = username
+                    .as_ref()
+                    .zip(req.app_data::<web::Data<PathBuf>>())
+                    .map(|(name, base)| base.join(name));
+                if folder.is_some_and(|f| is_sync_paused(&f)) {
+                    return Err(actix_web::error::ErrorServiceUnavailable(
+                        "a backup is in progress,try again in a moment",
+                    ));
+                }
+            }
+            let payload_size = sync_request.data.len();
+            let client_version = match &sync_request.media_client_version {
+                Some(v) if sync_request.client_version.is_empty() => v.clone(),
+                _ => sync_request.client_version.clone(),
+            };
+            let changes = changes_in_request(&method, &sync_request.data);
+            let audit = req.app_data::<web::Data<AuditLog>>().cloned();
             req.extensions_mut().insert(sync_request);
-            let res = service.call(req).await?;
+            let res = service.call(req).await;
+            let status = match &res {
+                Ok(r) => r.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            SyncEvent {
+                request_id: &request_id,
+                ip: client.ip,
+                https: client.https,
+                username: username.as_deref(),
+                method: &method,
+                client_version: &client_version,
+                payload_size,
+                duration_ms: start.elapsed().as_millis(),
+                status: status.as_u16(),
+                outcome: if status.is_success() { "ok" } else { "error" },
+            }
+            .emit();
+            if let (Some(audit), Some(username)) = (audit, &username) {
+                audit
+                    .observe(
+                        username,
+                        client.ip,
+                        &client_version,
+                        &method,
+                        changes,
+                        status.is_success(),
+                    )
+                    .await;
+            }
+            let mut res = res?;
+            if let Ok(v) = HeaderValue::from_str(&request_id) {
+                res.headers_mut()
+                    .insert(HeaderName::from_static("x-request-id"), v);
+            }
             Ok(res)
         })
     }
@@ -235,15 +342,19 @@ pub async fn host_key(
                     key: hash.to_string(),
                 })
             } else {
+                METRICS.inc_auth_failures();
                 Err(
                     UserError::Authentication(format!("Authentication failed for user {username}"))
                         .into(),
                 )
             }
         }
-        None => Err(UserError::Authentication(format!(
-            "Authentication failed for nonexistent user {username}"
-        ))
-        .into()),
+        None => {
+            METRICS.inc_auth_failures();
+            Err(UserError::Authentication(format!(
+                "Authentication failed for nonexistent user {username}"
+            ))
+            .into())
+        }
     }
 }
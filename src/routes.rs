@@ -1,7 +1,12 @@
 #![allow(clippy::await_holding_lock)]
 use crate::app_config::set_users;
+use crate::backup;
 use crate::db::fetch_users;
+use crate::dedup;
+use crate::metrics::{RequestTimer, METRICS};
+use crate::reload::LiveConfig;
 use crate::response::make_response;
+use crate::storage::{self, MediaStorage};
 
 use crate::{error::ApplicationError, request};
 use actix_web::http::StatusCode;
@@ -85,6 +90,7 @@ async fn begin_wrapper(
     req: SyncRequest<Vec<u8>>,
     server: web::Data<Arc<SimpleServer>>,
 ) -> actix_web::Result<HttpResponse> {
+    let mut timer = RequestTimer::new("msync", &MediaSyncMethod::Begin);
     let sync_version = req.sync_version;
     let data = server
         // .lock()
@@ -93,6 +99,7 @@ async fn begin_wrapper(
         .await
         .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
         .data;
+    timer.success();
     Ok(make_response(data, sync_version))
 }
 
@@ -100,12 +107,15 @@ pub async fn media_sync_handler(
     req: Option<web::ReqData<SyncRequest<Vec<u8>>>>,
     method: web::Path<MediaSyncMethod>, //(endpoint,sync_method)
     server: web::Data<Arc<SimpleServer>>,
+    live_config: web::Data<LiveConfig>,
+    storage: web::Data<Arc<dyn MediaStorage>>,
 ) -> actix_web::Result<HttpResponse> {
     let sync_method = method.into_inner();
+    let mut timer = RequestTimer::new("msync", &sync_method);
 
     let req = req.unwrap().into_inner();
     let sync_version = req.sync_version;
-    match sync_method {
+    let res = match sync_method {
         MediaSyncMethod::Begin => {
             // As begin and meta are two functions that are called rirst,so we do the error handling here.
             let data = server
@@ -116,7 +126,7 @@ pub async fn media_sync_handler(
                     _ => ApplicationError::InternalServerError(e.context),
                 })?
                 .data;
-            Ok(make_response(data, sync_version))
+            make_response(data, sync_version)
         }
         MediaSyncMethod::MediaChanges => {
             let data = server
@@ -126,9 +136,11 @@ pub async fn media_sync_handler(
                 .await
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
-            Ok(make_response(data, sync_version))
+            make_response(data, sync_version)
         }
         MediaSyncMethod::UploadChanges => {
+            let sync_key = req.sync_key.clone();
+            let upload = dedup::before_media_upload(&server, &sync_key, &req.data).await?;
             let data = server
                 // .lock()
                 // .expect("server call method")
@@ -136,17 +148,27 @@ pub async fn media_sync_handler(
                 .await
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
-            Ok(make_response(data, sync_version))
+            if let Some((folder, names)) = upload {
+                storage::after_media_upload(&storage, &server, &sync_key, &names).await?;
+                if let Some(store) = live_config.media_store() {
+                    dedup::after_media_upload(store, folder, names).await;
+                }
+            }
+            make_response(data, sync_version)
         }
         MediaSyncMethod::DownloadFiles => {
-            let data = server
+            let fetched =
+                storage::before_media_download(&storage, &server, &req.sync_key, &req.data).await?;
+            let result = server
                 // .lock()
                 // .expect("server call method")
                 .download_files(req.into_output_type())
-                .await
+                .await;
+            storage::after_media_download(fetched);
+            let data = result
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
-            Ok(make_response(data, sync_version))
+            make_response(data, sync_version)
         }
         MediaSyncMethod::MediaSanity => {
             let data = server
@@ -156,9 +178,11 @@ pub async fn media_sync_handler(
                 .await
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
-            Ok(make_response(data, sync_version))
+            make_response(data, sync_version)
         }
-    }
+    };
+    timer.success();
+    Ok(res)
 }
 
 pub async fn collecction_sync_handler(
@@ -167,8 +191,10 @@ pub async fn collecction_sync_handler(
     server: web::Data<Arc<SimpleServer>>,
     auth_db: web::Data<String>,
     base_folder: web::Data<PathBuf>,
+    live_config: web::Data<LiveConfig>,
 ) -> actix_web::Result<HttpResponse> {
     let sync_method = method.into_inner();
+    let mut timer = RequestTimer::new("sync", &sync_method);
     // let sync_method:SyncMethod=serde_json::from_str(&method.into_inner().0).unwrap();
     //  let o= req.0.into_output_type();
     let req = req.unwrap().into_inner();
@@ -222,6 +248,7 @@ pub async fn collecction_sync_handler(
             make_response(data, sync_version)
         }
         SyncMethod::Start => {
+            METRICS.inc_sync("normal");
             let data = server
                 // .lock()
                 // .expect("server call method")
@@ -282,6 +309,7 @@ pub async fn collecction_sync_handler(
             make_response(data, sync_version)
         }
         SyncMethod::Finish => {
+            let sync_key = req.sync_key.clone();
             let data = server
                 // .lock()
                 // .expect("server call method")
@@ -289,6 +317,7 @@ pub async fn collecction_sync_handler(
                 .await
                 .map_err(|e| ApplicationError::InternalServerError(e.to_string()))?
                 .data;
+            backup::after_sync(&server, &sync_key, live_config.backup_config()).await;
             make_response(data, sync_version)
         }
         SyncMethod::Abort => {
@@ -302,6 +331,8 @@ pub async fn collecction_sync_handler(
             make_response(data, sync_version)
         }
         SyncMethod::Upload => {
+            METRICS.inc_sync("full_upload");
+            backup::before_full_upload(&server, &req.sync_key, live_config.backup_config()).await;
             let data = server
                 // .lock()
                 // .expect("server call method")
@@ -313,6 +344,7 @@ pub async fn collecction_sync_handler(
             make_response(data, sync_version)
         }
         SyncMethod::Download => {
+            METRICS.inc_sync("full_download");
             let data = server
                 // .lock()
                 // .expect("server call method")
@@ -323,5 +355,6 @@ pub async fn collecction_sync_handler(
             make_response(data, sync_version)
         }
     };
+    timer.success();
     Ok(res)
 }
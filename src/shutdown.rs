@@ -0,0 +1,118 @@
+// graceful shutdown: let active syncs finish before closing every collection
+use actix_web::dev::ServerHandle;
+use actix_web::web;
+use anki::sync::http_server::SimpleServer;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::audit::AuditLog;
+use crate::reload::LiveConfig;
+use crate::systemd;
+
+/// Shared between the shutdown task and the sync middleware.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    draining: AtomicBool,
+}
+
+impl Shutdown {
+    /// whether new sync sessions are refused
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+}
+
+/// requests that begin a new sync session,refused while draining
+pub fn starts_session(method: &str) -> bool {
+    matches!(
+        method,
+        "hostKey" | "meta" | "start" | "upload" | "download" | "begin"
+    )
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        use futures_util::future::{select, Either};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                let term = Box::pin(term.recv());
+                let int = Box::pin(actix_web::rt::signal::ctrl_c());
+                match select(term, int).await {
+                    Either::Left(_) => log::info!("received SIGTERM"),
+                    Either::Right(_) => log::info!("received SIGINT"),
+                }
+                return;
+            }
+            Err(e) => log::error!("unable to listen for SIGTERM: {e}"),
+        }
+    }
+    let _ = actix_web::rt::signal::ctrl_c().await;
+    log::info!("received SIGINT");
+}
+
+fn active_sessions(server: &SimpleServer) -> usize {
+    let state = server.state.lock().expect("server state lock");
+    state
+        .users
+        .values()
+        .filter(|u| u.sync_state.is_some())
+        .count()
+}
+
+/// abort remaining syncs and close all collections and media databases
+fn close_all(server: &SimpleServer) {
+    let mut state = server.state.lock().expect("server state lock");
+    for user in state.users.values_mut() {
+        if user.sync_state.take().is_some() {
+            log::warn!("aborting unfinished sync of {}", user.name);
+        }
+        // the transaction of an aborted sync is rolled back when closing
+        if let Some(col) = user.col.take() {
+            if let Err(e) = col.close(None) {
+                log::error!("unable to close collection of {}: {e}", user.name);
+            }
+        }
+    }
+    // dropping the users closes their media databases
+    state.users.clear();
+}
+
+/// Wait for SIGTERM or SIGINT,then shut the server down.
+///
+/// New sync sessions are refused right away,active ones get the configured
+/// grace period to finish before being aborted.The sessions still open are
+/// written to the audit log.
+pub async fn graceful_shutdown(
+    handle: ServerHandle,
+    server: web::Data<Arc<SimpleServer>>,
+    shutdown: web::Data<Shutdown>,
+    live_config: web::Data<LiveConfig>,
+    audit: web::Data<AuditLog>,
+) {
+    wait_for_signal().await;
+    let grace = live_config.shutdown_grace_period();
+    systemd::notify("STOPPING=1");
+    shutdown.draining.store(true, Ordering::Relaxed);
+    let deadline = Instant::now() + grace;
+    let active = active_sessions(&server);
+    if active > 0 {
+        log::info!("waiting up to {grace:?} for {active} sync(s) to finish before shutting down");
+    }
+    loop {
+        let active = active_sessions(&server);
+        if active == 0 {
+            break;
+        }
+        if Instant::now() >= deadline {
+            log::warn!("grace period over,aborting {active} sync(s)");
+            break;
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(500)).await;
+    }
+    close_all(&server);
+    audit.flush().await;
+    handle.stop(true).await;
+}
@@ -0,0 +1,193 @@
+// systemd integration: socket activation and sd_notify,without linking libsystemd.
+// reference: https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html
+// and https://www.freedesktop.org/software/systemd/man/sd_notify.html
+use crate::error::ApplicationError;
+use std::env;
+use std::time::Duration;
+
+/// a listening socket passed by systemd
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+/// first file descriptor passed by systemd
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Number of sockets passed for the process `own_pid`,from `LISTEN_PID` and `LISTEN_FDS`.
+#[cfg(unix)]
+fn passed_fds(pid: Option<&str>, fds: Option<&str>, own_pid: u32) -> Result<i32, ApplicationError> {
+    // the sockets are meant for another process
+    if pid.and_then(|p| p.parse::<u32>().ok()) != Some(own_pid) {
+        return Ok(0);
+    }
+    match fds.map(|n| n.parse::<i32>()) {
+        Some(Ok(n)) if n >= 0 => Ok(n),
+        Some(_) => Err(ApplicationError::ParseConfig(
+            "invalid LISTEN_FDS passed by systemd".to_string(),
+        )),
+        None => Ok(0),
+    }
+}
+
+/// Take the ownership of a socket passed on `fd`.
+#[cfg(unix)]
+fn listener_from_fd(fd: i32) -> Result<Listener, ApplicationError> {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: zeroed sockaddr_storage is valid and getsockname writes at most len bytes
+    let family = unsafe {
+        let mut addr: libc::sockaddr_storage = std::mem::zeroed();
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        addr.ss_family as i32
+    };
+    // SAFETY: systemd hands the ownership of these descriptors over to us
+    match family {
+        libc::AF_UNIX => Ok(Listener::Unix(unsafe { FromRawFd::from_raw_fd(fd) })),
+        libc::AF_INET | libc::AF_INET6 => Ok(Listener::Tcp(unsafe { FromRawFd::from_raw_fd(fd) })),
+        _ => Err(ApplicationError::ParseConfig(format!(
+            "unsupported socket family passed by systemd on fd {fd}"
+        ))),
+    }
+}
+
+/// Take the sockets passed with `LISTEN_FDS`,empty when not socket activated.
+///
+/// The environment variables are removed so they are not inherited.
+#[cfg(unix)]
+pub fn listen_fds() -> Result<Vec<Listener>, ApplicationError> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let n = passed_fds(pid.as_deref(), fds.as_deref(), std::process::id())?;
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + n)
+        .map(listener_from_fd)
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> Result<Vec<Listener>, ApplicationError> {
+    Ok(vec![])
+}
+
+/// Send a state such as `READY=1` to the service manager,do nothing if not run by systemd.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    if let Err(e) = notify_socket(state) {
+        log::warn!("unable to notify systemd of {state}: {e}");
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn notify_socket(state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    match path.strip_prefix('@') {
+        // abstract socket namespace
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), &*path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Interval to send `WATCHDOG=1` at,half of the one asked by systemd.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Pet the watchdog from the server runtime,so a hung runtime stops petting and gets restarted.
+pub fn spawn_watchdog() {
+    if let Some(interval) = watchdog_interval() {
+        log::info!("petting systemd watchdog every {interval:?}");
+        actix_web::rt::spawn(async move {
+            let mut timer = actix_web::rt::time::interval(interval);
+            loop {
+                timer.tick().await;
+                notify("WATCHDOG=1");
+            }
+        });
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::{UnixDatagram, UnixListener};
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!("ankisyncd-{name}-{:016x}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn passed_fds_follow_listen_pid() {
+        assert_eq!(passed_fds(Some("42"), Some("2"), 42).unwrap(), 2);
+        assert_eq!(passed_fds(Some("41"), Some("2"), 42).unwrap(), 0);
+        assert_eq!(passed_fds(None, Some("2"), 42).unwrap(), 0);
+        assert_eq!(passed_fds(Some("42"), None, 42).unwrap(), 0);
+        assert!(passed_fds(Some("42"), Some("two"), 42).is_err());
+        assert!(passed_fds(Some("42"), Some("-1"), 42).is_err());
+    }
+
+    #[test]
+    fn passed_sockets_keep_their_family() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let path = temp_path("listen");
+        let unix = UnixListener::bind(&path).unwrap();
+        // SAFETY: the duplicates are owned by the listeners built from them
+        let (tcp_fd, unix_fd) =
+            unsafe { (libc::dup(tcp.as_raw_fd()), libc::dup(unix.as_raw_fd())) };
+        assert!(matches!(listener_from_fd(tcp_fd), Ok(Listener::Tcp(l))
+            if l.local_addr().unwrap() == tcp.local_addr().unwrap()));
+        assert!(matches!(listener_from_fd(unix_fd), Ok(Listener::Unix(_))));
+        assert!(listener_from_fd(-1).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn notify_sends_state_to_notify_socket() {
+        let path = temp_path("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // the only test using NOTIFY_SOCKET
+        env::set_var("NOTIFY_SOCKET", &path);
+        notify("READY=1");
+        notify("STATUS=serving");
+        env::remove_var("NOTIFY_SOCKET");
+        let mut buf = [0; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"STATUS=serving");
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,14 +1,18 @@
 #[cfg(feature = "account")]
 use crate::config::Account;
 
-use crate::parse_args::UserCommand;
+use crate::audit::{create_audit_table, record_or_log, AuditEntry};
+use crate::deck::create_deck_tables;
+use crate::group::create_group_tables;
+use crate::parse_args::Command;
 
 use rand::{rngs::OsRng, RngCore};
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
 use sha2::{Digest, Sha256};
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -68,7 +72,7 @@ fn add_user_to_auth_db<P: AsRef<Path>>(
 ) -> Result<(), UserError> {
     let salt = create_salt();
     let pass_hash = create_pass_hash(username, password, &salt);
-    let sql = "INSERT INTO auth VALUES (?, ?)";
+    let sql = "INSERT INTO auth (username,hash) VALUES (?, ?)";
     let conn = Connection::open(&dbpath)?;
     conn.execute(sql, [username, pass_hash.as_str()])?;
     conn.close()?;
@@ -95,43 +99,111 @@ fn del_user<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<(), UserError>
     let sql = "DELETE FROM auth WHERE username=?";
     let conn = Connection::open(dbpath)?;
     conn.execute(sql, [username])?;
+    conn.execute("DELETE FROM user_groups WHERE username=?", [username])?;
     conn.close()?;
     Ok(())
 }
+/// what a user may do besides syncing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Role {
+    #[default]
+    User,
+    /// server-wide with the auth table,of one group with the user_groups table
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = UserError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "admin" => Ok(Role::Admin),
+            _ => Err(UserError::MissingValues(format!(
+                "unknown role {s},use user or admin"
+            ))),
+        }
+    }
+}
+
+/// add the role column to an auth table created before roles existed
+fn add_role_column(conn: &Connection) -> Result<(), rusqlite::Error> {
+    let has_role = conn
+        .prepare("SELECT 1 FROM pragma_table_info('auth') WHERE name='role'")?
+        .exists([])?;
+    if !has_role {
+        conn.execute(
+            "ALTER TABLE auth ADD COLUMN role TEXT NOT NULL DEFAULT 'user'",
+            [],
+        )?;
+    }
+    Ok(())
+}
+
 pub fn create_auth_db<P: AsRef<Path>>(p: P) -> Result<(), UserError> {
     let sql = "CREATE TABLE IF NOT EXISTS auth
-(username VARCHAR PRIMARY KEY, hash VARCHAR)";
+(username VARCHAR PRIMARY KEY, hash VARCHAR, role TEXT NOT NULL DEFAULT 'user')";
     let conn = Connection::open(p)?;
     conn.execute(sql, [])?;
+    add_role_column(&conn)?;
+    create_audit_table(&conn)?;
+    create_group_tables(&conn)?;
+    create_deck_tables(&conn)?;
     conn.close()?;
 
     Ok(())
 }
 /// command-line user management
-pub fn user_manage<P: AsRef<Path>>(cmd: &UserCommand, dbpath: P) -> Result<(), UserError> {
-    match cmd {
-        UserCommand::User {
-            add,
-            del,
-            pass,
-            list,
-        } => {
-            if let Some(account) = add {
-                add_user(account, &dbpath)?;
-            }
-            if let Some(users) = del {
-                for u in users {
-                    del_user(u, &dbpath)?;
-                }
-            }
-            if let Some(account) = pass {
-                passwd(account, &dbpath)?;
+pub fn user_manage<P: AsRef<Path>>(cmd: &Command, dbpath: P) -> Result<(), UserError> {
+    if let Command::User {
+        add,
+        del,
+        pass,
+        list,
+        role,
+    } = cmd
+    {
+        if let Some(account) = add {
+            add_user(account, &dbpath)?;
+            record_or_log(
+                &dbpath,
+                &AuditEntry::event(&account[0], "admin", "ok").detail("user added"),
+            );
+        }
+        if let Some(users) = del {
+            for u in users {
+                del_user(u, &dbpath)?;
+                record_or_log(
+                    &dbpath,
+                    &AuditEntry::event(u, "admin", "ok").detail("user deleted"),
+                );
             }
-            if *list {
-                let user_list = user_list(&dbpath)?;
-                if let Some(v) = user_list {
-                    v.into_iter().for_each(|i| println!("{i}"));
-                }
+        }
+        if let Some(account) = pass {
+            passwd(account, &dbpath)?;
+            record_or_log(&dbpath, &AuditEntry::event(&account[0], "password", "ok"));
+        }
+        if let Some(args) = role {
+            let (username, role) = (&args[0], args[1].parse::<Role>()?);
+            set_role(username, role, &dbpath)?;
+            record_or_log(
+                &dbpath,
+                &AuditEntry::event(username, "admin", "ok")
+                    .detail(format!("role set to {}", role.as_str())),
+            );
+        }
+        if *list {
+            for (name, role) in user_roles(&dbpath)? {
+                println!("{name}\t{}", role.as_str());
             }
         }
     }
@@ -151,6 +223,42 @@ pub fn user_list<P: AsRef<Path>>(dbpath: P) -> Result<Option<Vec<String>>, UserE
         Ok(Some(v1))
     }
 }
+pub fn set_role<P: AsRef<Path>>(username: &str, role: Role, dbpath: P) -> Result<(), UserError> {
+    if !user_exists(username, &dbpath)? {
+        return Err(UserError::MissingValues(format!("no user {username}")));
+    }
+    let conn = Connection::open(dbpath)?;
+    conn.execute(
+        "UPDATE auth SET role=? WHERE username=?",
+        [role.as_str(), username],
+    )?;
+    Ok(())
+}
+
+/// every user with their role,sorted by name
+pub fn user_roles<P: AsRef<Path>>(dbpath: P) -> Result<Vec<(String, Role)>, UserError> {
+    let conn = Connection::open(dbpath)?;
+    let mut stmt = conn.prepare("SELECT username,role FROM auth ORDER BY username")?;
+    let rows = stmt.query_map([], |r| Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?)))?;
+    let mut users = vec![];
+    for row in rows {
+        let (name, role) = row?;
+        users.push((name, role.parse()?));
+    }
+    Ok(users)
+}
+
+/// role of a user,`None` if unknown
+pub fn user_role<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<Option<Role>, UserError> {
+    let conn = Connection::open(dbpath)?;
+    let role: Option<String> = conn
+        .query_row("SELECT role FROM auth WHERE username=?", [username], |r| {
+            r.get(0)
+        })
+        .optional()?;
+    role.map(|r| r.parse()).transpose()
+}
+
 pub fn user_exists<P: AsRef<Path>>(username: &str, dbpath: P) -> Result<bool, UserError> {
     let uservec = user_list(dbpath)?;
     match uservec {
@@ -170,6 +278,47 @@ fn create_pass_hash(username: &str, password: &str, salt: &str) -> String {
     let pass_hash = format!("{result:x}{salt}");
     pass_hash
 }
+/// the value stored in auth.db for a new password,salted with a random salt
+pub fn hash_password(username: &str, password: &str) -> String {
+    create_pass_hash(username, password, &create_salt())
+}
+
+/// Read a password from the terminal without echoing it.
+#[cfg(unix)]
+pub fn prompt_password(prompt: &str) -> io::Result<String> {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+    eprint!("{prompt}");
+    io::stderr().flush()?;
+    let fd = io::stdin().as_raw_fd();
+    // SAFETY: termios is plain data,filled by tcgetattr before use
+    let mut term: libc::termios = unsafe { std::mem::zeroed() };
+    let is_tty = unsafe { libc::tcgetattr(fd, &mut term) } == 0;
+    if is_tty {
+        let mut silent = term;
+        silent.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &silent) };
+    }
+    let mut line = String::new();
+    let result = io::stdin().read_line(&mut line);
+    if is_tty {
+        unsafe { libc::tcsetattr(fd, libc::TCSANOW, &term) };
+        eprintln!();
+    }
+    result?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(not(unix))]
+pub fn prompt_password(prompt: &str) -> io::Result<String> {
+    use std::io::Write;
+    eprint!("{prompt}");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
 /// extract salt from a hash which is the last 16 characters
 pub fn compute_hash(username: &str, password: &str, hash: &str) -> String {
     let salt = &hash[(hash.chars().count() - 16)..];